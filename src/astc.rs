@@ -0,0 +1,113 @@
+use super::ktx2::ASTC_BLOCK_SIZES;
+use super::MaterialAttribute;
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+
+/// The width and height of an ASTC block in texels. Larger blocks take less
+/// memory at a lower quality, down to 0.89 bits per texel for 12x12.
+#[derive(Clone, Copy, Debug, Deserialize, Hash, Eq, PartialEq, Serialize)]
+#[serde(try_from = "(u8, u8)")]
+pub struct AstcBlockSize(u8, u8);
+
+impl AstcBlockSize {
+    /// The width and height in texels.
+    pub fn dimensions(&self) -> (u8, u8) {
+        (self.0, self.1)
+    }
+
+    /// The block size used for `attr` when none is configured.
+    pub fn default_for(attr: MaterialAttribute) -> Self {
+        match attr {
+            MaterialAttribute::Normal => Self(4, 4),
+            MaterialAttribute::Albedo | MaterialAttribute::MetallicRoughness => Self(6, 6),
+            MaterialAttribute::AmbientOcclusion
+            | MaterialAttribute::Depth
+            | MaterialAttribute::Emissive
+            | MaterialAttribute::Metallic
+            | MaterialAttribute::Roughness => Self(8, 8),
+        }
+    }
+}
+
+impl TryFrom<(u8, u8)> for AstcBlockSize {
+    type Error = String;
+
+    fn try_from((width, height): (u8, u8)) -> Result<Self, Self::Error> {
+        if ASTC_BLOCK_SIZES.contains(&(width, height)) {
+            Ok(Self(width, height))
+        } else {
+            Err(format!("{width}x{height} is not an ASTC block size"))
+        }
+    }
+}
+
+impl fmt::Display for AstcBlockSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.0, self.1)
+    }
+}
+
+/// How hard the ASTC encoder searches for the best encoding of each block.
+#[derive(Clone, Copy, Debug, Deserialize, Hash, Eq, PartialEq, Serialize, ValueEnum)]
+pub enum AstcQuality {
+    Fastest,
+    Fast,
+    Medium,
+    Thorough,
+    Exhaustive,
+}
+
+impl fmt::Display for AstcQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fastest => write!(f, "fastest"),
+            Self::Fast => write!(f, "fast"),
+            Self::Medium => write!(f, "medium"),
+            Self::Thorough => write!(f, "thorough"),
+            Self::Exhaustive => write!(f, "exhaustive"),
+        }
+    }
+}
+
+/// A RON file of ASTC settings to share between the runs of a project, such as:
+///
+/// ```ron
+/// (
+///     quality: Some(Thorough),
+///     block_sizes: [(Normal, (4, 4)), (Albedo, (5, 5))],
+/// )
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AstcProfile {
+    #[serde(default)]
+    pub quality: Option<AstcQuality>,
+    #[serde(default)]
+    pub block_sizes: Vec<(MaterialAttribute, AstcBlockSize)>,
+}
+
+impl AstcProfile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("{path:?}"))?;
+        ron::de::from_reader(file).with_context(|| format!("{path:?}"))
+    }
+}
+
+/// Parse an `--astc-block-size` argument of the form
+/// `<attribute>=<width>x<height>`, such as `normal=4x4`.
+pub(crate) fn parse_block_size_arg(
+    arg: &str,
+) -> Result<(MaterialAttribute, AstcBlockSize), String> {
+    let (name, size) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected <attribute>=<width>x<height>, got {arg:?}"))?;
+    let attr = MaterialAttribute::parse_canonical_name(name)?;
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .ok_or_else(|| format!("expected <width>x<height>, got {size:?}"))?;
+    Ok((attr, AstcBlockSize::try_from((width, height))?))
+}
@@ -0,0 +1,164 @@
+use crate::encoder::{find_backend, run_command, EncodeJob, Executable, TextureEncoder};
+use crate::ktx2::{Ktx2Texture, VkFormat};
+use crate::plan::{PlanStep, Planner};
+use crate::{
+    AstcBlockSize, EncodeOptions, EncoderBackend, Ktx2TextureCodec, MaterialAttribute,
+    MipGeneration, TextureFormat,
+};
+use anyhow::Context;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+const ASTC_MAGIC: [u8; 4] = [0x13, 0xAB, 0xA1, 0x5C];
+const ASTC_HEADER_LEN: usize = 16;
+
+/// Arm's releases name "astcenc" after the instruction set it was built for.
+const EXECUTABLE: Executable = Executable {
+    names: &[
+        "astcenc",
+        "astcenc-avx2",
+        "astcenc-sse4.1",
+        "astcenc-sse2",
+        "astcenc-neon",
+        "astcenc-native",
+    ],
+    flag: "--astcenc-path",
+    env_var: "ASTCENC",
+    install: "Install it from https://github.com/ARM-software/astc-encoder/releases",
+};
+
+/// Find "astcenc" if it encodes any of the outputs of `texture_format`.
+pub(crate) fn prepare(
+    encode: &EncodeOptions,
+    texture_format: TextureFormat,
+    planner: &Planner,
+) -> anyhow::Result<Option<PathBuf>> {
+    find_backend(
+        EncoderBackend::Astcenc,
+        &EXECUTABLE,
+        encode.astcenc_path.as_deref(),
+        encode,
+        texture_format,
+        planner,
+    )
+}
+
+/// Encodes ASTC with the "astcenc" tool from Arm.
+///
+/// "astcenc" only writes single images, so each level of each layer is encoded
+/// to its own ".astc" file, and the blocks are assembled into a KTX2 file
+/// in-process.
+pub struct Astcenc;
+
+impl TextureEncoder for Astcenc {
+    fn supports(&self, codec: Ktx2TextureCodec) -> bool {
+        codec == Ktx2TextureCodec::Astc
+    }
+
+    fn check_mips(&self, attr: MaterialAttribute, mips: MipGeneration) -> anyhow::Result<()> {
+        if mips == MipGeneration::Toktx {
+            anyhow::bail!(
+                "astcenc can't generate the mips of {attr:?}; use --mips native or --mips none"
+            );
+        }
+        Ok(())
+    }
+
+    fn encode(&self, job: &EncodeJob, planner: &Planner) -> anyhow::Result<()> {
+        let attr = job.attribute;
+        let block_size = job.encode.astc_block_size(attr);
+        let srgb = attr == MaterialAttribute::Albedo;
+
+        let staged_paths: Vec<Vec<PathBuf>> = job
+            .layer_paths
+            .iter()
+            .enumerate()
+            .map(|(layer, levels)| {
+                (0..levels.len())
+                    .map(|level| staged_path(job, layer, level))
+                    .collect()
+            })
+            .collect();
+
+        let program = job
+            .encode
+            .astcenc_path
+            .as_deref()
+            .unwrap_or(Path::new("astcenc"));
+        for (input_levels, staged_levels) in job.layer_paths.iter().zip(&staged_paths) {
+            for (input, staged) in input_levels.iter().zip(staged_levels) {
+                let mut args: Vec<OsString> = vec![
+                    if srgb { "-cs" } else { "-cl" }.into(),
+                    input.into(),
+                    staged.into(),
+                    block_size.to_string().into(),
+                    format!("-{}", job.encode.astc_quality()).into(),
+                ];
+                if matches!(attr, MaterialAttribute::Albedo | MaterialAttribute::Normal) {
+                    args.push("-perceptual".into());
+                }
+                run_command(program, &args, job, planner)?;
+            }
+        }
+
+        planner.record(PlanStep::WriteFile {
+            path: job.output_path.to_owned(),
+        });
+        if planner.is_dry_run() {
+            return Ok(());
+        }
+
+        let mut dimensions = (0, 0);
+        let mut levels = vec![Vec::new(); job.num_levels()];
+        for staged_levels in &staged_paths {
+            for (level, path) in staged_levels.iter().enumerate() {
+                let (level_dimensions, blocks) = read_astc(path, block_size)?;
+                if level == 0 {
+                    dimensions = level_dimensions;
+                }
+                levels[level].extend_from_slice(&blocks);
+                std::fs::remove_file(path).with_context(|| format!("{path:?}"))?;
+            }
+        }
+
+        let texture = Ktx2Texture {
+            format: VkFormat::Astc { block_size, srgb },
+            width: dimensions.0,
+            height: dimensions.1,
+            layers: job.is_array.then_some(job.layer_paths.len() as u32),
+            levels,
+            zstd_level: job.encode.zstd,
+        };
+        texture.write(&job.partial_path())
+    }
+}
+
+/// Where "astcenc" writes a level of a layer of the job's output.
+fn staged_path(job: &EncodeJob, layer: usize, level: usize) -> PathBuf {
+    let name = job.output_path.file_stem().unwrap_or_default();
+    let mut file_name = name.to_owned();
+    file_name.push(format!(".layer{layer}.mip{level}.astc"));
+    job.staging_directory.join(file_name)
+}
+
+/// Read the dimensions and blocks of an ".astc" file.
+fn read_astc(path: &Path, block_size: AstcBlockSize) -> anyhow::Result<((u32, u32), Vec<u8>)> {
+    let mut bytes = std::fs::read(path).with_context(|| format!("{path:?}"))?;
+    if bytes.len() < ASTC_HEADER_LEN || bytes[0..4] != ASTC_MAGIC {
+        anyhow::bail!("{path:?} is not an ASTC file");
+    }
+    let (width, height) = block_size.dimensions();
+    if bytes[4..7] != [width, height, 1] {
+        anyhow::bail!(
+            "{path:?} has {}x{} blocks instead of {block_size}",
+            bytes[4],
+            bytes[5]
+        );
+    }
+    let dimension = |offset: usize| {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], 0])
+    };
+    let dimensions = (dimension(7), dimension(10));
+    let blocks = bytes.split_off(ASTC_HEADER_LEN);
+    Ok((dimensions, blocks))
+}
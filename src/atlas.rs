@@ -0,0 +1,235 @@
+use crate::raster;
+use crate::{LayoutOptions, UvRect};
+use image::{DynamicImage, ImageBuffer, Pixel};
+
+/// Where the layers of a grid atlas go.
+///
+/// Each cell holds one layer surrounded by a gutter, and cells are filled
+/// along each row from the top left.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AtlasGrid {
+    pub columns: u32,
+    pub rows: u32,
+    pub layer_dimensions: (u32, u32),
+    pub gutter: u32,
+}
+
+impl AtlasGrid {
+    pub fn new(num_layers: usize, layer_dimensions: (u32, u32), layout: &LayoutOptions) -> Self {
+        let num_layers = num_layers.max(1) as u32;
+        let columns = layout
+            .atlas_columns
+            .unwrap_or_else(|| (num_layers as f64).sqrt().ceil() as u32)
+            .min(num_layers);
+        Self {
+            columns,
+            rows: (num_layers + columns - 1) / columns,
+            layer_dimensions,
+            gutter: layout.atlas_gutter,
+        }
+    }
+
+    fn cell_dimensions(&self) -> (u32, u32) {
+        let (width, height) = self.layer_dimensions;
+        (width + 2 * self.gutter, height + 2 * self.gutter)
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        let (cell_width, cell_height) = self.cell_dimensions();
+        (self.columns * cell_width, self.rows * cell_height)
+    }
+
+    /// The top left texel of `layer`, inside the gutter.
+    fn layer_origin(&self, layer: usize) -> (u32, u32) {
+        let (cell_width, cell_height) = self.cell_dimensions();
+        let (column, row) = (layer as u32 % self.columns, layer as u32 / self.columns);
+        (
+            column * cell_width + self.gutter,
+            row * cell_height + self.gutter,
+        )
+    }
+
+    pub fn uv_rect(&self, layer: usize) -> UvRect {
+        let (width, height) = self.dimensions();
+        let (x, y) = self.layer_origin(layer);
+        let (layer_width, layer_height) = self.layer_dimensions;
+        UvRect {
+            min: (x as f32 / width as f32, y as f32 / height as f32),
+            max: (
+                (x + layer_width) as f32 / width as f32,
+                (y + layer_height) as f32 / height as f32,
+            ),
+        }
+    }
+
+    /// Lay `layers` out in the grid, in RGBA with the widest channels of any
+    /// layer so that no bit depth is lost. The gutters repeat the opposite
+    /// edges of each layer, and unused cells are transparent black.
+    pub fn pack(&self, layers: &[DynamicImage]) -> DynamicImage {
+        match raster::channel_bytes(layers) {
+            1 => DynamicImage::ImageRgba8(self.pack_as(layers, DynamicImage::to_rgba8)),
+            2 => DynamicImage::ImageRgba16(self.pack_as(layers, DynamicImage::to_rgba16)),
+            _ => DynamicImage::ImageRgba32F(self.pack_as(layers, DynamicImage::to_rgba32f)),
+        }
+    }
+
+    fn pack_as<P: Pixel>(
+        &self,
+        layers: &[DynamicImage],
+        convert: impl Fn(&DynamicImage) -> ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let (width, height) = self.dimensions();
+        let mut atlas = ImageBuffer::new(width, height);
+        let (layer_width, layer_height) = self.layer_dimensions;
+        let gutter = self.gutter as i64;
+
+        for (i, layer) in layers.iter().enumerate() {
+            let layer = convert(layer);
+            let (origin_x, origin_y) = self.layer_origin(i);
+            for y in -gutter..layer_height as i64 + gutter {
+                for x in -gutter..layer_width as i64 + gutter {
+                    let src_x = x.rem_euclid(layer_width as i64) as u32;
+                    let src_y = y.rem_euclid(layer_height as i64) as u32;
+                    atlas.put_pixel(
+                        (origin_x as i64 + x) as u32,
+                        (origin_y as i64 + y) as u32,
+                        *layer.get_pixel(src_x, src_y),
+                    );
+                }
+            }
+        }
+
+        atlas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use image::{Rgba, RgbaImage};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        layout: LayoutOptions,
+    }
+
+    fn grid(num_layers: usize, args: &[&str]) -> AtlasGrid {
+        let cli = Cli::parse_from(["test", "--layout", "grid"].iter().chain(args));
+        AtlasGrid::new(num_layers, (4, 2), &cli.layout)
+    }
+
+    /// A layer whose texels all differ, and differ from every other layer.
+    fn layer(index: u8) -> RgbaImage {
+        RgbaImage::from_fn(4, 2, |x, y| Rgba([x as u8, y as u8, index, 255]))
+    }
+
+    #[test]
+    fn columns_and_rows() {
+        let grid_of = |num_layers, args| {
+            let grid = grid(num_layers, args);
+            (grid.columns, grid.rows)
+        };
+        assert_eq!(grid_of(5, &[]), (3, 2));
+        assert_eq!(grid_of(4, &[]), (2, 2));
+        assert_eq!(grid_of(5, &["--atlas-columns", "4"]), (4, 2));
+        // No more columns than layers.
+        assert_eq!(grid_of(3, &["--atlas-columns", "8"]), (3, 1));
+    }
+
+    #[test]
+    fn dimensions_include_gutters() {
+        assert_eq!(grid(5, &[]).dimensions(), (12, 4));
+        assert_eq!(grid(5, &["--atlas-gutter", "1"]).dimensions(), (18, 8));
+    }
+
+    #[test]
+    fn uv_rect_covers_the_cell_without_its_gutter() {
+        let grid = grid(5, &["--atlas-gutter", "1"]);
+        // Layer 4 is the second cell of the second row, with its texels
+        // starting at (7, 5) in an 18x8 atlas.
+        let rect = grid.uv_rect(4);
+        assert_eq!(rect.min, (7.0 / 18.0, 5.0 / 8.0));
+        assert_eq!(rect.max, (11.0 / 18.0, 7.0 / 8.0));
+
+        let rect = grid.uv_rect(0);
+        assert_eq!(rect.min, (1.0 / 18.0, 1.0 / 8.0));
+        assert_eq!(rect.max, (5.0 / 18.0, 3.0 / 8.0));
+    }
+
+    #[test]
+    fn gutters_wrap_from_the_opposite_edge() {
+        let grid = grid(5, &["--atlas-gutter", "1"]);
+        let layers: Vec<DynamicImage> =
+            (0..5).map(|i| DynamicImage::ImageRgba8(layer(i))).collect();
+        let atlas = grid.pack(&layers).to_rgba8();
+        assert_eq!(atlas.dimensions(), grid.dimensions());
+
+        let expected = layer(4);
+        let (origin_x, origin_y) = (7, 5);
+        for y in 0..2 {
+            for x in 0..4 {
+                assert_eq!(
+                    atlas.get_pixel(origin_x + x, origin_y + y),
+                    expected.get_pixel(x, y)
+                );
+            }
+        }
+        // Left and right gutters repeat the right and left edges.
+        for y in 0..2 {
+            assert_eq!(
+                atlas.get_pixel(origin_x - 1, origin_y + y),
+                expected.get_pixel(3, y)
+            );
+            assert_eq!(
+                atlas.get_pixel(origin_x + 4, origin_y + y),
+                expected.get_pixel(0, y)
+            );
+        }
+        // Top and bottom gutters repeat the bottom and top edges.
+        for x in 0..4 {
+            assert_eq!(
+                atlas.get_pixel(origin_x + x, origin_y - 1),
+                expected.get_pixel(x, 1)
+            );
+            assert_eq!(
+                atlas.get_pixel(origin_x + x, origin_y + 2),
+                expected.get_pixel(x, 0)
+            );
+        }
+        // Corners repeat the opposite corner.
+        assert_eq!(
+            atlas.get_pixel(origin_x - 1, origin_y - 1),
+            expected.get_pixel(3, 1)
+        );
+
+        // The unused last cell is transparent black.
+        for y in 4..8 {
+            for x in 12..18 {
+                assert_eq!(atlas.get_pixel(x, y), &Rgba([0; 4]));
+            }
+        }
+    }
+
+    #[test]
+    fn packs_at_the_widest_channel_depth() {
+        let grid = grid(2, &[]);
+        let gray16 =
+            DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(4, 2, image::Luma([257])));
+        let rgba8 = DynamicImage::ImageRgba8(layer(1));
+        assert!(matches!(
+            grid.pack(&[rgba8.clone(), rgba8.clone()]),
+            DynamicImage::ImageRgba8(_)
+        ));
+        let DynamicImage::ImageRgba16(atlas) = grid.pack(&[gray16, rgba8]) else {
+            panic!("16-bit layers should be packed in 16 bits");
+        };
+        assert_eq!(atlas.get_pixel(0, 0), &Rgba([257, 257, 257, u16::MAX]));
+        let float = DynamicImage::ImageRgb32F(image::Rgb32FImage::new(4, 2));
+        assert!(matches!(
+            grid.pack(&[float, layer(0).into()]),
+            DynamicImage::ImageRgba32F(_)
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use crate::encoder::{find_backend, run_command, EncodeJob, Executable, TextureEncoder};
+use crate::plan::Planner;
+use crate::{
+    EncodeOptions, EncoderBackend, Ktx2TextureCodec, MaterialAttribute, MipGeneration,
+    TextureFormat,
+};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+const EXECUTABLE: Executable = Executable {
+    names: &["basisu"],
+    flag: "--basisu-path",
+    env_var: "BASISU",
+    install: "Build it from https://github.com/BinomialLLC/basis_universal",
+};
+
+/// Find "basisu" if it encodes any of the outputs of `texture_format`.
+pub(crate) fn prepare(
+    encode: &EncodeOptions,
+    texture_format: TextureFormat,
+    planner: &Planner,
+) -> anyhow::Result<Option<PathBuf>> {
+    find_backend(
+        EncoderBackend::Basisu,
+        &EXECUTABLE,
+        encode.basisu_path.as_deref(),
+        encode,
+        texture_format,
+        planner,
+    )
+}
+
+/// Encodes UASTC and ETC1S with the "basisu" tool from Basis Universal.
+pub struct Basisu;
+
+impl TextureEncoder for Basisu {
+    fn supports(&self, codec: Ktx2TextureCodec) -> bool {
+        matches!(codec, Ktx2TextureCodec::Uastc | Ktx2TextureCodec::Etc1s)
+    }
+
+    fn check_mips(&self, attr: MaterialAttribute, mips: MipGeneration) -> anyhow::Result<()> {
+        if mips == MipGeneration::Native {
+            anyhow::bail!(
+                "basisu can't be given pre-generated mips for {attr:?}; use --mips toktx to let \
+                 it generate them, or --mips none"
+            );
+        }
+        Ok(())
+    }
+
+    fn encode(&self, job: &EncodeJob, planner: &Planner) -> anyhow::Result<()> {
+        let encode = job.encode;
+        let mut args: Vec<OsString> = vec!["-ktx2".into()];
+        match job.codec {
+            Ktx2TextureCodec::Uastc => {
+                args.extend([
+                    "-uastc".into(),
+                    "-uastc_level".into(),
+                    encode.uastc_quality.to_string().into(),
+                ]);
+                if encode.uastc_rdo_lambda > 0.0 {
+                    args.extend([
+                        "-uastc_rdo_l".into(),
+                        encode.uastc_rdo_lambda.to_string().into(),
+                    ]);
+                }
+                // basisu supercompresses UASTC by default.
+                match encode.zstd {
+                    Some(level) => {
+                        args.extend(["-ktx2_zstandard_level".into(), level.to_string().into()])
+                    }
+                    None => args.push("-ktx2_no_zstandard".into()),
+                }
+            }
+            Ktx2TextureCodec::Etc1s => args.extend([
+                "-comp_level".into(),
+                encode.etc1s_compression.to_string().into(),
+                "-q".into(),
+                encode.etc1s_quality.to_string().into(),
+            ]),
+            Ktx2TextureCodec::Astc => unreachable!("basisu doesn't encode ASTC"),
+        }
+
+        // Everything but albedo is linear, which basisu doesn't assume.
+        match job.attribute {
+            MaterialAttribute::Albedo => {}
+            MaterialAttribute::Normal => args.push("-normal_map".into()),
+            _ => args.push("-linear".into()),
+        }
+        if job.mips == MipGeneration::Toktx {
+            args.push("-mipmap".into());
+        }
+        if job.is_array {
+            args.extend(["-tex_type".into(), "2darray".into()]);
+        }
+
+        args.extend(["-output_file".into(), job.partial_path().into()]);
+        args.extend(job.layer_paths.iter().map(|p| p[0].clone().into()));
+
+        let program = job
+            .encode
+            .basisu_path
+            .as_deref()
+            .unwrap_or(Path::new("basisu"));
+        run_command(program, &args, job, planner)
+    }
+}
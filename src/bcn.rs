@@ -0,0 +1,365 @@
+use image::{DynamicImage, RgbaImage};
+use rayon::prelude::*;
+
+/// A block compression format. Every format encodes 4x4 blocks of texels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BcFormat {
+    /// RGB with 5:6:5 endpoints and 4 colors per block, without alpha.
+    Bc1,
+    /// A single channel, taken from red.
+    Bc4,
+    /// Two channels, taken from red and green.
+    Bc5,
+    /// RGBA, encoded with mode 6 only: one subset with 7-bit endpoints and
+    /// 16 colors per block.
+    Bc7,
+}
+
+impl BcFormat {
+    pub fn block_bytes(&self) -> usize {
+        match self {
+            Self::Bc1 | Self::Bc4 => 8,
+            Self::Bc5 | Self::Bc7 => 16,
+        }
+    }
+}
+
+type Block = [[u8; 4]; 16];
+
+/// Compress `img` into row-major blocks of `format`. Edge blocks of images that
+/// aren't a multiple of 4 texels repeat the last row and column.
+pub fn compress(format: BcFormat, img: &DynamicImage) -> Vec<u8> {
+    let rgba = img.to_rgba8();
+    let blocks_wide = (rgba.width() + 3) / 4;
+    let blocks_high = (rgba.height() + 3) / 4;
+
+    let rows: Vec<Vec<u8>> = (0..blocks_high)
+        .into_par_iter()
+        .map(|by| {
+            let mut row = Vec::with_capacity(blocks_wide as usize * format.block_bytes());
+            for bx in 0..blocks_wide {
+                let block = read_block(&rgba, bx, by);
+                match format {
+                    BcFormat::Bc1 => row.extend_from_slice(&bc1_block(&block)),
+                    BcFormat::Bc4 => row.extend_from_slice(&bc4_block(&block, 0)),
+                    BcFormat::Bc5 => {
+                        row.extend_from_slice(&bc4_block(&block, 0));
+                        row.extend_from_slice(&bc4_block(&block, 1));
+                    }
+                    BcFormat::Bc7 => row.extend_from_slice(&bc7_mode6_block(&block)),
+                }
+            }
+            row
+        })
+        .collect();
+    rows.concat()
+}
+
+fn read_block(img: &RgbaImage, bx: u32, by: u32) -> Block {
+    let mut block = [[0; 4]; 16];
+    for (i, texel) in block.iter_mut().enumerate() {
+        let x = (bx * 4 + i as u32 % 4).min(img.width() - 1);
+        let y = (by * 4 + i as u32 / 4).min(img.height() - 1);
+        *texel = img.get_pixel(x, y).0;
+    }
+    block
+}
+
+/// The two ends of the line through `points` along their principal axis,
+/// spanning every point's projection onto it.
+fn principal_endpoints<const N: usize>(points: &[[f32; N]; 16]) -> ([f32; N], [f32; N]) {
+    let mut mean = [0.0; N];
+    for point in points {
+        for (m, c) in mean.iter_mut().zip(point) {
+            *m += c / 16.0;
+        }
+    }
+
+    let mut covariance = [[0.0; N]; N];
+    for point in points {
+        for i in 0..N {
+            for j in 0..N {
+                covariance[i][j] += (point[i] - mean[i]) * (point[j] - mean[j]);
+            }
+        }
+    }
+
+    // Power iteration converges on the axis of greatest variance.
+    let mut axis = [1.0; N];
+    for _ in 0..8 {
+        let mut next = [0.0; N];
+        for (n, row) in next.iter_mut().zip(&covariance) {
+            *n = row.iter().zip(&axis).map(|(c, a)| c * a).sum();
+        }
+        let len = next.iter().map(|c| c * c).sum::<f32>().sqrt();
+        if len < f32::EPSILON {
+            break;
+        }
+        axis = next.map(|c| c / len);
+    }
+    let len = axis.iter().map(|c| c * c).sum::<f32>().sqrt();
+    axis = axis.map(|c| c / len);
+
+    let (mut t_min, mut t_max) = (f32::MAX, f32::MIN);
+    for point in points {
+        let t: f32 = (0..N).map(|i| (point[i] - mean[i]) * axis[i]).sum();
+        t_min = t_min.min(t);
+        t_max = t_max.max(t);
+    }
+    let end = |t: f32| {
+        let mut end = [0.0; N];
+        for i in 0..N {
+            end[i] = (mean[i] + axis[i] * t).clamp(0.0, 255.0);
+        }
+        end
+    };
+    (end(t_min), end(t_max))
+}
+
+/// The index of the color in `palette` nearest to `texel`.
+fn nearest<const N: usize>(palette: &[[f32; N]], texel: &[f32; N]) -> usize {
+    let distance = |color: &[f32; N]| -> f32 {
+        color
+            .iter()
+            .zip(texel)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum()
+    };
+    (0..palette.len())
+        .min_by(|&a, &b| distance(&palette[a]).total_cmp(&distance(&palette[b])))
+        .unwrap()
+}
+
+fn bc1_block(block: &Block) -> [u8; 8] {
+    let colors = block.map(|[r, g, b, _]| [r as f32, g as f32, b as f32]);
+    let (low, high) = principal_endpoints(&colors);
+
+    let to_565 = |[r, g, b]: [f32; 3]| {
+        let r = (r * 31.0 / 255.0).round() as u16;
+        let g = (g * 63.0 / 255.0).round() as u16;
+        let b = (b * 31.0 / 255.0).round() as u16;
+        r << 11 | g << 5 | b
+    };
+    let from_565 = |c: u16| {
+        let r = (c >> 11) & 31;
+        let g = (c >> 5) & 63;
+        let b = c & 31;
+        [
+            (r * 255 / 31) as f32,
+            (g * 255 / 63) as f32,
+            (b * 255 / 31) as f32,
+        ]
+    };
+
+    // The 4-color mode needs the first endpoint to be the greater one.
+    let mut c0 = to_565(high);
+    let mut c1 = to_565(low);
+    if c0 < c1 {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+
+    let mut indices = 0u32;
+    if c0 != c1 {
+        let p0 = from_565(c0);
+        let p1 = from_565(c1);
+        let lerp = |a: f32, b: f32| {
+            let mut color = [0.0; 3];
+            for i in 0..3 {
+                color[i] = (a * p0[i] + b * p1[i]) / 3.0;
+            }
+            color
+        };
+        let palette = [p0, p1, lerp(2.0, 1.0), lerp(1.0, 2.0)];
+        for (i, texel) in colors.iter().enumerate() {
+            indices |= (nearest(&palette, texel) as u32) << (2 * i);
+        }
+    }
+
+    let mut out = [0; 8];
+    out[0..2].copy_from_slice(&c0.to_le_bytes());
+    out[2..4].copy_from_slice(&c1.to_le_bytes());
+    out[4..8].copy_from_slice(&indices.to_le_bytes());
+    out
+}
+
+/// Encode `channel` of the block, in the 8-value mode.
+fn bc4_block(block: &Block, channel: usize) -> [u8; 8] {
+    let values = block.map(|texel| texel[channel]);
+    let max = *values.iter().max().unwrap();
+    let min = *values.iter().min().unwrap();
+
+    let mut out = [max, min, 0, 0, 0, 0, 0, 0];
+    if max == min {
+        return out;
+    }
+
+    let mut palette = [[0.0f32; 1]; 8];
+    palette[0] = [max as f32];
+    palette[1] = [min as f32];
+    for (k, color) in palette.iter_mut().enumerate().skip(2) {
+        *color = [((8 - k) as f32 * max as f32 + (k - 1) as f32 * min as f32) / 7.0];
+    }
+
+    let mut indices = 0u64;
+    for (i, value) in values.iter().enumerate() {
+        indices |= (nearest(&palette, &[*value as f32]) as u64) << (3 * i);
+    }
+    out[2..8].copy_from_slice(&indices.to_le_bytes()[..6]);
+    out
+}
+
+const BC7_WEIGHTS_4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+fn bc7_mode6_block(block: &Block) -> [u8; 16] {
+    let texels = block.map(|texel| texel.map(|c| c as f32));
+    let (low, high) = principal_endpoints(&texels);
+
+    // Each endpoint is 7 bits per channel plus a shared least significant
+    // p-bit, so pick whichever p-bit gets closer.
+    let quantize = |end: [f32; 4]| -> ([u8; 4], u8) {
+        let mut best = ([0; 4], 0, f32::MAX);
+        for p in 0..2u8 {
+            let q = end.map(|c| ((c - p as f32) / 2.0).round().clamp(0.0, 127.0) as u8);
+            let error: f32 = q
+                .iter()
+                .zip(end)
+                .map(|(q, c)| {
+                    let d = (q << 1 | p) as f32 - c;
+                    d * d
+                })
+                .sum();
+            if error < best.2 {
+                best = (q, p, error);
+            }
+        }
+        (best.0, best.1)
+    };
+    let mut ends = [quantize(low), quantize(high)];
+
+    let palette_of = |ends: &[([u8; 4], u8); 2]| {
+        let e0 = ends[0].0.map(|c| (c << 1 | ends[0].1) as u32);
+        let e1 = ends[1].0.map(|c| (c << 1 | ends[1].1) as u32);
+        BC7_WEIGHTS_4.map(|w| {
+            let mut color = [0.0; 4];
+            for i in 0..4 {
+                color[i] = (((64 - w) * e0[i] + w * e1[i] + 32) >> 6) as f32;
+            }
+            color
+        })
+    };
+    let palette = palette_of(&ends);
+    let mut indices = texels.map(|texel| nearest(&palette, &texel) as u8);
+
+    // The first index is stored without its most significant bit, so it has
+    // to be below 8.
+    if indices[0] >= 8 {
+        ends.swap(0, 1);
+        indices = indices.map(|i| 15 - i);
+    }
+
+    let mut bits = BitWriter::default();
+    bits.write(1 << 6, 7);
+    for channel in 0..4 {
+        bits.write(ends[0].0[channel] as u128, 7);
+        bits.write(ends[1].0[channel] as u128, 7);
+    }
+    bits.write(ends[0].1 as u128, 1);
+    bits.write(ends[1].1 as u128, 1);
+    bits.write(indices[0] as u128, 3);
+    for index in &indices[1..] {
+        bits.write(*index as u128, 4);
+    }
+    bits.value.to_le_bytes()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    value: u128,
+    len: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u128, count: u32) {
+        self.value |= bits << self.len;
+        self.len += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode a BC7 mode 6 block, the only mode we write.
+    fn decode_bc7_mode6(bytes: [u8; 16]) -> Block {
+        let mut value = u128::from_le_bytes(bytes);
+        let mut read = |count: u32| {
+            let bits = value & ((1 << count) - 1);
+            value >>= count;
+            bits as u32
+        };
+        assert_eq!(read(7), 1 << 6, "not a mode 6 block");
+        let mut ends = [[0u32; 4]; 2];
+        for channel in 0..4 {
+            ends[0][channel] = read(7);
+            ends[1][channel] = read(7);
+        }
+        for end in &mut ends {
+            let p = read(1);
+            *end = end.map(|c| c << 1 | p);
+        }
+        let mut block = [[0; 4]; 16];
+        for (i, texel) in block.iter_mut().enumerate() {
+            let w = BC7_WEIGHTS_4[read(if i == 0 { 3 } else { 4 }) as usize];
+            *texel =
+                std::array::from_fn(|c| (((64 - w) * ends[0][c] + w * ends[1][c] + 32) >> 6) as u8);
+        }
+        block
+    }
+
+    fn max_error(a: &Block, b: &Block) -> u8 {
+        a.iter()
+            .flatten()
+            .zip(b.iter().flatten())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn bc7_solid_block() {
+        let block = [[200, 100, 51, 255]; 16];
+        let decoded = decode_bc7_mode6(bc7_mode6_block(&block));
+        assert!(max_error(&block, &decoded) <= 1, "{decoded:?}");
+    }
+
+    #[test]
+    fn bc7_gradient_block() {
+        let block: Block = std::array::from_fn(|i| {
+            let t = i as u8 * 16;
+            [t, 255 - t, t / 2, 255]
+        });
+        let decoded = decode_bc7_mode6(bc7_mode6_block(&block));
+        assert!(max_error(&block, &decoded) <= 4, "{decoded:?}");
+    }
+
+    #[test]
+    fn bc4_block_keeps_its_extremes() {
+        let block: Block = std::array::from_fn(|i| [if i % 2 == 0 { 10 } else { 240 }, 0, 0, 0]);
+        let bytes = bc4_block(&block, 0);
+        assert_eq!(bytes[..2], [240, 10]);
+        let indices = u64::from_le_bytes([
+            bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], 0, 0,
+        ]);
+        for i in 0..16 {
+            let expected = if i % 2 == 0 { 1 } else { 0 };
+            assert_eq!((indices >> (3 * i)) & 7, expected);
+        }
+    }
+
+    #[test]
+    fn compress_pads_edge_blocks() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(5, 3, image::Rgba([9; 4])));
+        assert_eq!(compress(BcFormat::Bc7, &img).len(), 2 * 16);
+        assert_eq!(compress(BcFormat::Bc1, &img).len(), 2 * 8);
+    }
+}
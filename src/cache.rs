@@ -0,0 +1,145 @@
+use crate::output;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// The "build-cache.ron" file kept in an output directory.
+///
+/// Maps the file name of each output to a hash of everything it was built
+/// from, so outputs whose inputs haven't changed can be skipped. Delete the
+/// file to force a full rebuild.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct BuildCache {
+    entries: BTreeMap<PathBuf, String>,
+    /// The other files written along with an output, such as its mip levels,
+    /// by the file name of the output. Only outputs with other files are
+    /// listed.
+    #[serde(default)]
+    files: BTreeMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl BuildCache {
+    pub fn path(directory: &Path) -> PathBuf {
+        directory.join("build-cache").with_extension("ron")
+    }
+
+    /// Returns an empty cache if there is no readable cache file.
+    pub fn load(directory: &Path) -> Self {
+        let cache_path = Self::path(directory);
+        let Ok(file) = File::open(&cache_path) else {
+            return Self::default();
+        };
+        ron::de::from_reader(file).unwrap_or_else(|e| {
+            eprintln!("Ignoring unreadable build cache {cache_path:?}: {e}");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, directory: &Path) -> anyhow::Result<()> {
+        let cache_path = Self::path(directory);
+        output::write(
+            &cache_path,
+            ron::ser::to_string_pretty(self, Default::default())
+                .with_context(|| format!("Couldn't serialize {cache_path:?}"))?,
+        )
+    }
+
+    /// True if all of `outputs` exist and were last built from inputs matching
+    /// `key`.
+    pub fn is_up_to_date(&self, key: &CacheKey, outputs: &[PathBuf]) -> bool {
+        outputs.iter().all(|output| {
+            output.is_file()
+                && output
+                    .file_name()
+                    .and_then(|name| self.entries.get(Path::new(name)))
+                    == Some(&key.0)
+        })
+    }
+
+    /// Record that `outputs` were built from inputs matching `key`.
+    ///
+    /// The first of `outputs` is the output itself, and the rest are written
+    /// along with it. Files that were written along with it last time but
+    /// not this time, such as mip levels that are no longer generated, are
+    /// deleted.
+    pub fn insert(&mut self, key: &CacheKey, outputs: &[PathBuf]) -> anyhow::Result<()> {
+        let Some(output) = outputs.first() else {
+            return Ok(());
+        };
+        let directory = output.parent().unwrap_or(Path::new(""));
+        let names: Vec<PathBuf> = outputs
+            .iter()
+            .filter_map(|output| output.file_name())
+            .map(PathBuf::from)
+            .collect();
+        let Some((name, others)) = names.split_first() else {
+            return Ok(());
+        };
+
+        let previous = match others.is_empty() {
+            true => self.files.remove(name),
+            false => self.files.insert(name.clone(), others.to_vec()),
+        };
+        for stale in previous.iter().flatten().filter(|n| !others.contains(n)) {
+            self.entries.remove(stale);
+            let path = directory.join(stale);
+            match std::fs::remove_file(&path) {
+                Ok(()) => eprintln!("Removed {path:?}, which is no longer written"),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("{path:?}")),
+            }
+        }
+
+        for name in names {
+            self.entries.insert(name, key.0.clone());
+        }
+        Ok(())
+    }
+}
+
+/// A hash of the contents of some input files and the settings used to
+/// convert them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CacheKey(String);
+
+pub struct CacheKeyBuilder {
+    hasher: Sha256,
+}
+
+impl Default for CacheKeyBuilder {
+    fn default() -> Self {
+        let mut hasher = Sha256::new();
+        // Any change to the tool might change its outputs.
+        hasher.update(env!("CARGO_PKG_VERSION"));
+        Self { hasher }
+    }
+}
+
+impl CacheKeyBuilder {
+    pub fn file(mut self, path: &Path) -> anyhow::Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("{path:?}"))?;
+        let len =
+            std::io::copy(&mut file, &mut self.hasher).with_context(|| format!("{path:?}"))?;
+        self.hasher.update(len.to_le_bytes());
+        Ok(self)
+    }
+
+    /// Hashes the `Debug` representation of `setting`.
+    pub fn setting(mut self, setting: &impl std::fmt::Debug) -> Self {
+        self.hasher.update(format!("{setting:?}\n"));
+        self
+    }
+
+    pub fn finish(self) -> CacheKey {
+        use std::fmt::Write;
+
+        let mut hex = String::new();
+        for b in self.hasher.finalize() {
+            write!(hex, "{b:02x}").unwrap();
+        }
+        CacheKey(hex)
+    }
+}
@@ -1,105 +1,619 @@
 use super::{MaterialAttribute, MaterialFormat};
-use crate::toktx::toktx2;
-use crate::{Ktx2TextureCodec, TextureFormat};
+use crate::cache::{BuildCache, CacheKeyBuilder};
+use crate::dds::DdsTexture;
+use crate::encoder::{self, EncodeJob};
+use crate::interrupt;
+use crate::ktx2::{Ktx2Texture, VkFormat};
+use crate::mipmap::{bake_normal_variance, generate_mips, mip_path, preserve_alpha_coverage};
+use crate::output;
+use crate::plan::{PlanStep, Planner};
+use crate::raster;
+use crate::staging::StagingDir;
+use crate::{
+    Adjustment, EncodeOptions, Ktx2TextureCodec, MaterialMetadata, MetalRoughOptions,
+    MipGeneration, MipOptions, ResamplePolicy, TextureFormat,
+};
 use anyhow::Context;
-use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+use rayon::prelude::*;
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
+#[allow(clippy::too_many_arguments)]
 pub fn convert_images(
     assignment_file: &Path,
     material_format: MaterialFormat,
     texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    metal_rough_options: &MetalRoughOptions,
     output_directory: &Path,
+    planner: &Planner,
 ) -> anyhow::Result<()> {
+    mips.validate()?;
+    let encode = &encode.resolve(texture_format, mips, planner)?;
+
+    let assignments: Vec<(MaterialAttribute, PathBuf)> = ron::de::from_reader(
+        File::open(assignment_file)
+            .with_context(|| assignment_file.to_string_lossy().into_owned())?,
+    )?;
+
+    convert_assignments(
+        &assignments,
+        material_format,
+        texture_format,
+        mips,
+        encode,
+        metal_rough_options,
+        output_directory,
+        planner,
+    )?;
+
+    Ok(())
+}
+
+/// Like `convert_images`, but with the assignments already in memory. Returns
+/// the metadata of the converted material, which is also written to
+/// `output_directory` unless this is a dry run.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn convert_assignments(
+    assignments: &[(MaterialAttribute, PathBuf)],
+    material_format: MaterialFormat,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    metal_rough_options: &MetalRoughOptions,
+    output_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<MaterialMetadata> {
     match material_format {
-        MaterialFormat::BevyPbr => {
-            convert_images_to_bevy_pbr(assignment_file, texture_format, output_directory)
-        }
+        MaterialFormat::BevyPbr => convert_images_to_bevy_pbr(
+            assignments,
+            texture_format,
+            mips,
+            encode,
+            metal_rough_options,
+            output_directory,
+            planner,
+        ),
     }
 }
 
 fn convert_images_to_bevy_pbr(
-    assignment_file: &Path,
+    assignments: &[(MaterialAttribute, PathBuf)],
     texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    metal_rough_options: &MetalRoughOptions,
     output_directory: &Path,
-) -> anyhow::Result<()> {
-    std::fs::create_dir_all(output_directory)?;
+    planner: &Planner,
+) -> anyhow::Result<MaterialMetadata> {
+    if !planner.is_dry_run() {
+        std::fs::create_dir_all(output_directory)?;
+    }
 
-    let assignments: Vec<(MaterialAttribute, PathBuf)> = ron::de::from_reader(
-        File::open(assignment_file)
-            .with_context(|| assignment_file.to_string_lossy().into_owned())?,
-    )?;
+    // Each attribute is written to the same file whatever its source, so only
+    // one source per attribute can be converted in parallel.
+    let assignments = &dedup_assignments(assignments);
 
-    let mut metadata = Vec::new();
-    for (attr, path) in &assignments {
-        if let MaterialAttribute::Metallic | MaterialAttribute::Roughness = attr {
-            // These are handled separately.
-            continue;
+    // Outputs whose sources and settings haven't changed since the last run
+    // are skipped.
+    let cache = Mutex::new(BuildCache::load(output_directory));
+    let staging = StagingDir::new(output_directory, encode.keep_intermediates, planner)?;
+
+    // Convert each attribute in parallel, while also combining the metallic
+    // and roughness images.
+    let (images, metal_rough) = rayon::join(
+        || {
+            assignments
+                .par_iter()
+                .filter(|(attr, _)| {
+                    // These are handled separately.
+                    !matches!(
+                        attr,
+                        MaterialAttribute::Metallic | MaterialAttribute::Roughness
+                    )
+                })
+                .map(|(attr, path)| {
+                    convert_image(
+                        *attr,
+                        path,
+                        texture_format,
+                        mips,
+                        encode,
+                        output_directory,
+                        staging.path(),
+                        &cache,
+                        planner,
+                    )
+                    .map(|dims| (*attr, path.clone(), dims))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        },
+        || {
+            convert_metal_rough(
+                assignments,
+                texture_format,
+                mips,
+                encode,
+                metal_rough_options,
+                output_directory,
+                staging.path(),
+                &cache,
+                planner,
+            )
+        },
+    );
+
+    let (metal_rough, adjustments) = metal_rough?;
+    let mut metadata = MaterialMetadata {
+        images: images?,
+        adjustments,
+    };
+    metadata.images.extend(metal_rough);
+
+    planner.record(PlanStep::WriteFile {
+        path: MaterialMetadata::path(output_directory),
+    });
+    if !planner.is_dry_run() {
+        metadata.save(output_directory)?;
+        cache.into_inner().unwrap().save(output_directory)?;
+    }
+
+    Ok(metadata)
+}
+
+/// Keep only the last source assigned to each attribute, warning about the
+/// others.
+fn dedup_assignments(
+    assignments: &[(MaterialAttribute, PathBuf)],
+) -> Vec<(MaterialAttribute, PathBuf)> {
+    let mut deduped: Vec<(MaterialAttribute, PathBuf)> = Vec::with_capacity(assignments.len());
+    for (attr, path) in assignments {
+        match deduped.iter_mut().find(|(a, _)| a == attr) {
+            Some((_, previous)) => {
+                eprintln!(
+                    "Warning: {previous:?} and {path:?} are both assigned to {attr:?}, using \
+                     {path:?}"
+                );
+                *previous = path.clone();
+            }
+            None => deduped.push((*attr, path.clone())),
         }
+    }
+    deduped
+}
+
+/// Convert the image at `path` for `attr` and write it into
+/// `output_directory`, staging any images for the encoder in
+/// `staging_directory`. Returns the dimensions of the image.
+#[allow(clippy::too_many_arguments)]
+fn convert_image(
+    attr: MaterialAttribute,
+    path: &Path,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    output_directory: &Path,
+    staging_directory: &Path,
+    cache: &Mutex<BuildCache>,
+    planner: &Planner,
+) -> anyhow::Result<(u32, u32)> {
+    let dimensions = image::image_dimensions(path).with_context(|| format!("{path:?}"))?;
+    let output = ConvertedOutput::new(
+        attr,
+        dimensions,
+        texture_format,
+        mips,
+        encode,
+        output_directory,
+        staging_directory,
+    );
+    let outputs = output.files();
+
+    let key = CacheKeyBuilder::default()
+        .setting(&attr)
+        .setting(&texture_format)
+        .setting(&mips.cache_settings(attr))
+        .setting(&encode.cache_settings(attr, texture_format))
+        .file(path)?
+        .finish();
+    let up_to_date = cache.lock().unwrap().is_up_to_date(&key, &outputs);
 
+    planner.record(PlanStep::ConvertImage {
+        attribute: attr,
+        sources: vec![path.to_owned()],
+        dimensions,
+        mip_levels: output.num_levels,
+        adjustments: Vec::new(),
+        output: output.path().to_owned(),
+        up_to_date,
+    });
+    if up_to_date {
+        eprintln!("Skipping {attr:?}, output is up to date");
+        return Ok(dimensions);
+    }
+    interrupt::check()?;
+
+    if !planner.is_dry_run() {
         let img = image::open(path).with_context(|| format!("{path:?}"))?;
-        let converted_img = attr.convert_image(&img);
-        let new_name = attr.canonical_name();
+        output.write(attr, &img, mips, encode, None)?;
+    }
+    output.encode(attr, mips, encode, staging_directory, planner)?;
+
+    if !planner.is_dry_run() {
+        cache.lock().unwrap().insert(&key, &outputs)?;
+    }
+
+    Ok(dimensions)
+}
+
+/// The files the converted image of an attribute is written to.
+struct ConvertedOutput {
+    num_levels: usize,
+    texture_format: TextureFormat,
+    /// The format of the KTX2 or DDS file, if it is written in-process.
+    native_format: Option<VkFormat>,
+    /// The codec of the KTX2 file, if it is encoded by a `TextureEncoder`.
+    codec: Option<Ktx2TextureCodec>,
+    /// The image of each mip level, which are either the output itself, for
+    /// raster texture formats, or the PNG input to the encoder in the staging
+    /// directory.
+    levels: Vec<PathBuf>,
+    /// The KTX2 or DDS file, for texture formats that aren't raster formats.
+    texture: Option<PathBuf>,
+}
+
+impl ConvertedOutput {
+    fn new(
+        attr: MaterialAttribute,
+        dimensions: (u32, u32),
+        texture_format: TextureFormat,
+        mips: &MipOptions,
+        encode: &EncodeOptions,
+        output_directory: &Path,
+        staging_directory: &Path,
+    ) -> Self {
+        let num_levels = mips.level_count(texture_format, dimensions);
+        let native_format = VkFormat::for_texture_format(texture_format, attr, encode);
+        let (levels_directory, levels_extension) = match texture_format.is_raster() {
+            true => (output_directory, texture_format.extension()),
+            false => (staging_directory, "png"),
+        };
+        let levels = match native_format {
+            Some(_) => Vec::new(),
+            None => (0..num_levels)
+                .map(|level| mip_path(levels_directory, attr, level, levels_extension))
+                .collect(),
+        };
+        let texture = match texture_format.is_raster() {
+            true => None,
+            false => Some(mip_path(
+                output_directory,
+                attr,
+                0,
+                texture_format.extension(),
+            )),
+        };
+        Self {
+            num_levels,
+            texture_format,
+            native_format,
+            codec: texture_format.encoder_codec(attr),
+            levels,
+            texture,
+        }
+    }
 
-        match texture_format {
-            TextureFormat::Png => {
-                let new_path = output_directory.join(new_name).with_extension("png");
-                converted_img.save(new_path)?;
+    /// The final output.
+    fn path(&self) -> &Path {
+        self.texture.as_ref().unwrap_or_else(|| &self.levels[0])
+    }
+
+    /// Every file that is written to the output directory.
+    fn files(&self) -> Vec<PathBuf> {
+        match &self.texture {
+            Some(texture) => vec![texture.clone()],
+            None => self.levels.clone(),
+        }
+    }
+
+    /// Convert `img` for `attr` and write it, along with any mip levels. PNG
+    /// levels are written for the encoder rather than encoded; see `encode`.
+    ///
+    /// Given a `normal` map, its variance is baked into the roughness of the
+    /// mips of a metal_rough image, as by `bake_normal_variance`. The alpha
+    /// coverage of albedo mips is preserved if `mips` has an alpha cutoff.
+    fn write(
+        &self,
+        attr: MaterialAttribute,
+        img: &DynamicImage,
+        mips: &MipOptions,
+        encode: &EncodeOptions,
+        normal: Option<&DynamicImage>,
+    ) -> anyhow::Result<()> {
+        let levels = if self.num_levels == 1 {
+            vec![self.texture_format.convert_image(attr, img)]
+        } else {
+            let mut levels = generate_mips(attr, img, self.texture_format);
+            if let Some(normal) = normal {
+                bake_normal_variance(normal, &mut levels, mips.specular_aa_strength);
+            }
+            if let (MaterialAttribute::Albedo, Some(cutoff)) = (attr, mips.alpha_cutoff) {
+                preserve_alpha_coverage(&mut levels, cutoff);
             }
-            TextureFormat::Ktx2Astc => {
-                // Start by writing a PNG so we can invoke the "toktx" tool on it.
-                let png_path = output_directory.join(new_name).with_extension("png");
-                converted_img.save(&png_path)?;
+            levels
+        };
 
-                let output_path = png_path.with_extension("ktx2");
-                toktx2(&png_path, *attr, Ktx2TextureCodec::Astc, &output_path)?;
+        if let Some(format) = self.native_format {
+            write_native_texture(
+                self.texture_format,
+                format,
+                &[levels],
+                false,
+                encode,
+                self.path(),
+            )?;
+        } else {
+            for (level, path) in levels.iter().zip(&self.levels) {
+                raster::save(level, path)?;
             }
         }
+        Ok(())
+    }
 
-        metadata.push((*attr, path.clone(), img.dimensions()));
+    /// Encode the PNG levels, if the texture format needs it.
+    fn encode(
+        &self,
+        attr: MaterialAttribute,
+        mips: &MipOptions,
+        encode: &EncodeOptions,
+        staging_directory: &Path,
+        planner: &Planner,
+    ) -> anyhow::Result<()> {
+        match (&self.texture, self.codec) {
+            (Some(ktx2), Some(codec)) => {
+                let job = EncodeJob {
+                    attribute: attr,
+                    codec,
+                    layer_paths: &[self.levels.clone()],
+                    is_array: false,
+                    mips: mips.generation,
+                    encode,
+                    output_path: ktx2,
+                    staging_directory,
+                };
+                encoder::encode(&job, planner)
+            }
+            _ => Ok(()),
+        }
     }
+}
 
-    if let Some(img) = combine_metal_blue_rough_green(&assignments)? {
-        let img_path = output_directory
-            .join(MaterialAttribute::MetallicRoughness.canonical_name())
-            .with_extension("png");
-        img.save(&img_path)?;
-        metadata.push((
-            MaterialAttribute::MetallicRoughness,
-            img_path,
-            img.dimensions(),
-        ));
+/// Write the mip levels of each layer to a KTX2 or DDS file in `format`,
+/// depending on `texture_format`.
+pub(crate) fn write_native_texture(
+    texture_format: TextureFormat,
+    format: VkFormat,
+    layer_levels: &[Vec<DynamicImage>],
+    is_array: bool,
+    encode: &EncodeOptions,
+    path: &Path,
+) -> anyhow::Result<()> {
+    if texture_format.is_dds() {
+        let texture = DdsTexture::from_layer_levels(format, layer_levels)?;
+        output::write_with(path, |partial_path| texture.write(partial_path))
+    } else {
+        let mut texture = Ktx2Texture::from_layer_levels(format, layer_levels, is_array)?;
+        texture.zstd_level = encode.zstd;
+        output::write_with(path, |partial_path| texture.write(partial_path))
     }
+}
 
-    let meta_path = output_directory.join("metadata").with_extension("ron");
-    std::fs::write(
-        &meta_path,
-        ron::ser::to_string_pretty(&metadata, Default::default())?,
-    )
-    .with_context(|| format!("{meta_path:?}"))?;
+/// Combine the metallic and roughness images and write the result into
+/// `output_directory`, unless the previous result is up to date.
+///
+/// Returns the metadata entry for the metal_rough image, if one was made, and
+/// any adjustments that were needed to make it.
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
+fn convert_metal_rough(
+    assignments: &[(MaterialAttribute, PathBuf)],
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    options: &MetalRoughOptions,
+    output_directory: &Path,
+    staging_directory: &Path,
+    cache: &Mutex<BuildCache>,
+    planner: &Planner,
+) -> anyhow::Result<(
+    Option<(MaterialAttribute, PathBuf, (u32, u32))>,
+    Vec<Adjustment>,
+)> {
+    let metal = find_attribute(assignments, MaterialAttribute::Metallic);
+    let rough = find_attribute(assignments, MaterialAttribute::Roughness);
+    // Specular anti-aliasing needs the normal map too.
+    let normal = find_attribute(assignments, MaterialAttribute::Normal)
+        .filter(|_| mips.specular_aa && mips.generation == MipGeneration::Native);
 
-    Ok(())
+    let mut adjustments = Vec::new();
+    let Some(dimensions) = plan_metal_rough(metal, rough, options, &mut adjustments)? else {
+        return Ok((None, adjustments));
+    };
+
+    let attr = MaterialAttribute::MetallicRoughness;
+    let output = ConvertedOutput::new(
+        attr,
+        dimensions,
+        texture_format,
+        mips,
+        encode,
+        output_directory,
+        staging_directory,
+    );
+    let outputs = output.files();
+
+    let sizes_match = !adjustments
+        .iter()
+        .any(|adjustment| matches!(adjustment, Adjustment::Resampled { .. }));
+    let mut key = CacheKeyBuilder::default()
+        .setting(&texture_format)
+        .setting(&options.cache_settings(metal.is_some(), rough.is_some(), sizes_match))
+        .setting(&mips.cache_settings(MaterialAttribute::MetallicRoughness))
+        .setting(&encode.cache_settings(MaterialAttribute::MetallicRoughness, texture_format));
+    for (attr, source) in [
+        (MaterialAttribute::Metallic, metal),
+        (MaterialAttribute::Roughness, rough),
+        (MaterialAttribute::Normal, normal),
+    ] {
+        key = key.setting(&attr);
+        if let Some(path) = source {
+            key = key.file(path)?;
+        }
+    }
+    let key = key.finish();
+    let up_to_date = cache.lock().unwrap().is_up_to_date(&key, &outputs);
+
+    planner.record(PlanStep::ConvertImage {
+        attribute: attr,
+        sources: metal
+            .into_iter()
+            .chain(rough)
+            .chain(normal)
+            .cloned()
+            .collect(),
+        dimensions,
+        mip_levels: output.num_levels,
+        adjustments: adjustments.clone(),
+        output: output.path().to_owned(),
+        up_to_date,
+    });
+    if up_to_date {
+        eprintln!("Skipping MetallicRoughness, output is up to date");
+    } else {
+        interrupt::check()?;
+        if !planner.is_dry_run() {
+            let img = combine_metal_blue_rough_green(metal, rough, options, dimensions)?;
+            let normal = normal
+                .map(|path| image::open(path).with_context(|| format!("{path:?}")))
+                .transpose()?;
+            output.write(attr, &img, mips, encode, normal.as_ref())?;
+        }
+        output.encode(attr, mips, encode, staging_directory, planner)?;
+        if !planner.is_dry_run() {
+            cache.lock().unwrap().insert(&key, &outputs)?;
+        }
+    }
+
+    Ok((
+        Some((attr, output.path().to_owned(), dimensions)),
+        adjustments,
+    ))
 }
 
-/// Write the metal and rough grayscale values into the blue and green channels.
-fn combine_metal_blue_rough_green(
-    assignments: &[(MaterialAttribute, PathBuf)],
-) -> anyhow::Result<Option<DynamicImage>> {
-    let metal = open_attribute(assignments, MaterialAttribute::Metallic)?;
-    let rough = open_attribute(assignments, MaterialAttribute::Roughness)?;
+/// Decide the dimensions of the metal_rough image from the headers of the
+/// metallic and roughness images, without decoding them.
+///
+/// If only one of the two images exists, the other channel will be filled with
+/// the constant from `options`. If the two images have different sizes, one of
+/// them will be resampled according to `options`. Either way, what will be
+/// done is recorded in `adjustments`.
+fn plan_metal_rough(
+    metal: Option<&PathBuf>,
+    rough: Option<&PathBuf>,
+    options: &MetalRoughOptions,
+    adjustments: &mut Vec<Adjustment>,
+) -> anyhow::Result<Option<(u32, u32)>> {
+    let dimensions =
+        |path: &PathBuf| image::image_dimensions(path).with_context(|| format!("{path:?}"));
 
-    let (Some(metal), Some(rough)) = (metal, rough) else {
-        return Ok(None);
+    let (metal_dims, rough_dims) = match (metal, rough) {
+        (Some(metal), Some(rough)) => (dimensions(metal)?, dimensions(rough)?),
+        (Some(metal), None) => {
+            let metal_dims = dimensions(metal)?;
+            synthesize_channel(
+                MaterialAttribute::Roughness,
+                options.roughness_fill,
+                adjustments,
+            )?;
+            return Ok(Some(metal_dims));
+        }
+        (None, Some(rough)) => {
+            let rough_dims = dimensions(rough)?;
+            synthesize_channel(
+                MaterialAttribute::Metallic,
+                options.metallic_fill,
+                adjustments,
+            )?;
+            return Ok(Some(rough_dims));
+        }
+        (None, None) => return Ok(None),
     };
 
-    if metal.dimensions() != rough.dimensions() {
-        return Ok(None);
+    if metal_dims == rough_dims {
+        return Ok(Some(metal_dims));
     }
 
-    let mut metal_rough = RgbImage::new(metal.width(), metal.height());
-    let metal_gray = metal.to_luma8();
-    let rough_gray = rough.to_luma8();
+    let (metal_w, metal_h) = metal_dims;
+    let (rough_w, rough_h) = rough_dims;
+    if options.strict {
+        anyhow::bail!(
+            "Metallic image is {metal_w}x{metal_h} but roughness image is {rough_w}x{rough_h}"
+        );
+    }
+
+    let metal_is_larger = metal_w as u64 * metal_h as u64 > rough_w as u64 * rough_h as u64;
+    let resample_metal = match options.resample {
+        ResamplePolicy::Larger => !metal_is_larger,
+        ResamplePolicy::Smaller => metal_is_larger,
+    };
+    let (attribute, from, to) = if resample_metal {
+        (MaterialAttribute::Metallic, metal_dims, rough_dims)
+    } else {
+        (MaterialAttribute::Roughness, rough_dims, metal_dims)
+    };
+    eprintln!(
+        "WARNING: Metallic image is {metal_w}x{metal_h} but roughness image is \
+         {rough_w}x{rough_h}, resampling {attribute:?} from {}x{} to {}x{}",
+        from.0, from.1, to.0, to.1
+    );
+    adjustments.push(Adjustment::Resampled {
+        attribute,
+        from,
+        to,
+    });
+
+    Ok(Some(to))
+}
+
+/// Write the metal and rough grayscale values into the blue and green channels.
+///
+/// A missing image is replaced with the fill value from `options`, and any
+/// image not matching `dimensions` is resampled, as decided by
+/// `plan_metal_rough`.
+fn combine_metal_blue_rough_green(
+    metal: Option<&PathBuf>,
+    rough: Option<&PathBuf>,
+    options: &MetalRoughOptions,
+    (width, height): (u32, u32),
+) -> anyhow::Result<DynamicImage> {
+    let open_channel = |path: Option<&PathBuf>, fill: f32| -> anyhow::Result<GrayImage> {
+        let Some(path) = path else {
+            let gray = (fill * 255.0).round() as u8;
+            return Ok(GrayImage::from_pixel(width, height, Luma([gray])));
+        };
+        let mut img = image::open(path).with_context(|| format!("{path:?}"))?;
+        if img.dimensions() != (width, height) {
+            img = img.resize_exact(width, height, FilterType::Lanczos3);
+        }
+        Ok(img.to_luma8())
+    };
+    let metal_gray = open_channel(metal, options.metallic_fill)?;
+    let rough_gray = open_channel(rough, options.roughness_fill)?;
+
+    let mut metal_rough = RgbImage::new(width, height);
     for (x, y, pixel) in metal_rough.enumerate_pixels_mut() {
         *pixel = Rgb([
             0,
@@ -108,16 +622,30 @@ fn combine_metal_blue_rough_green(
         ]);
     }
 
-    Ok(Some(DynamicImage::ImageRgb8(metal_rough)))
+    Ok(DynamicImage::ImageRgb8(metal_rough))
+}
+
+/// Check the constant that will stand in for a missing channel.
+fn synthesize_channel(
+    attribute: MaterialAttribute,
+    value: f32,
+    adjustments: &mut Vec<Adjustment>,
+) -> anyhow::Result<()> {
+    if !(0.0..=1.0).contains(&value) {
+        anyhow::bail!("{attribute:?} fill value {value} is not in the range [0, 1]");
+    }
+
+    eprintln!("No {attribute:?} image found, filling the channel with {value}");
+    adjustments.push(Adjustment::Synthesized { attribute, value });
+
+    Ok(())
 }
 
-fn open_attribute(
+fn find_attribute(
     assignments: &[(MaterialAttribute, PathBuf)],
-    open_attr: MaterialAttribute,
-) -> anyhow::Result<Option<DynamicImage>> {
+    find_attr: MaterialAttribute,
+) -> Option<&PathBuf> {
     assignments
         .iter()
-        .find_map(|(attr, path)| (*attr == open_attr).then_some(path))
-        .map(|path| image::open(path).with_context(|| format!("{path:?}")))
-        .transpose()
+        .find_map(|(attr, path)| (*attr == find_attr).then_some(path))
 }
@@ -0,0 +1,221 @@
+use crate::ktx2::VkFormat;
+use anyhow::Context;
+use image::DynamicImage;
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"DDS ";
+const HEADER_LEN: u32 = 124;
+const PIXEL_FORMAT_LEN: u32 = 32;
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSD_LINEARSIZE: u32 = 0x80000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x400000;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+
+/// A 2D texture or 2D array texture with a full or partial mip chain, written
+/// with the DX10 header extension so that the format is a `DXGI_FORMAT`.
+pub struct DdsTexture {
+    pub format: VkFormat,
+    pub width: u32,
+    pub height: u32,
+    /// The texel data of each layer, each with the data of its mip levels
+    /// starting with the base level.
+    pub layers: Vec<Vec<Vec<u8>>>,
+}
+
+impl DdsTexture {
+    /// Build a texture from the mip levels of each layer.
+    pub fn from_layer_levels(
+        format: VkFormat,
+        layer_levels: &[Vec<DynamicImage>],
+    ) -> anyhow::Result<Self> {
+        let Some(first_layer) = layer_levels.first() else {
+            anyhow::bail!("Can't make a DDS texture with no layers");
+        };
+        let Some(base) = first_layer.first() else {
+            anyhow::bail!("Can't make a DDS texture with no mip levels");
+        };
+        let num_levels = first_layer.len();
+        if layer_levels.iter().any(|levels| levels.len() != num_levels) {
+            anyhow::bail!("All layers of a DDS texture need the same number of mip levels");
+        }
+
+        let layers = layer_levels
+            .iter()
+            .map(|levels| levels.iter().map(|img| format.image_bytes(img)).collect())
+            .collect();
+
+        Ok(Self {
+            format,
+            width: base.width(),
+            height: base.height(),
+            layers,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes()).with_context(|| format!("{path:?}"))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let num_levels = self.layers.first().map_or(0, Vec::len) as u32;
+        let is_compressed = self.format.block_compression().is_some();
+
+        let mut flags = DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT;
+        // The pitch of a row of texels, or the size of the whole base level
+        // for block-compressed formats.
+        let pitch_or_linear_size = if is_compressed {
+            flags |= DDSD_LINEARSIZE;
+            self.layers[0][0].len() as u32
+        } else {
+            flags |= DDSD_PITCH;
+            self.width * self.format.block_bytes()
+        };
+        let mut caps = DDSCAPS_TEXTURE;
+        if num_levels > 1 {
+            flags |= DDSD_MIPMAPCOUNT;
+            caps |= DDSCAPS_COMPLEX | DDSCAPS_MIPMAP;
+        }
+
+        let data_len: usize = self.layers.iter().flatten().map(Vec::len).sum();
+        let mut out = Vec::with_capacity(4 + HEADER_LEN as usize + 20 + data_len);
+        out.extend_from_slice(MAGIC);
+
+        let mut header = vec![
+            HEADER_LEN,
+            flags,
+            self.height,
+            self.width,
+            pitch_or_linear_size,
+            0, // depth
+            num_levels,
+        ];
+        header.extend([0; 11]); // reserved
+        header.extend([
+            PIXEL_FORMAT_LEN,
+            DDPF_FOURCC,
+            u32::from_le_bytes(*b"DX10"),
+            0, // RGB bit count
+            0, // red mask
+            0, // green mask
+            0, // blue mask
+            0, // alpha mask
+        ]);
+        header.extend([caps, 0, 0, 0, 0]); // caps2, caps3, caps4 and reserved
+
+        let dx10_header = [
+            dxgi_format(self.format),
+            D3D10_RESOURCE_DIMENSION_TEXTURE2D,
+            0, // misc flags
+            self.layers.len() as u32,
+            0, // alpha mode: unknown
+        ];
+
+        for value in header.into_iter().chain(dx10_header) {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+
+        // Unlike KTX2, each layer is stored with all of its mip levels.
+        for data in self.layers.iter().flatten() {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+}
+
+/// The `DXGI_FORMAT` value with the same layout as `format`.
+fn dxgi_format(format: VkFormat) -> u32 {
+    match format {
+        VkFormat::R8Unorm => 61,
+        VkFormat::R8G8Unorm => 49,
+        VkFormat::R8G8B8A8Unorm => 28,
+        VkFormat::R8G8B8A8Srgb => 29,
+        VkFormat::R16Unorm => 56,
+        // BC1 blocks in the 4-color mode are opaque.
+        VkFormat::Bc1RgbUnorm => 71,
+        VkFormat::Bc4Unorm => 80,
+        VkFormat::Bc5Unorm => 83,
+        VkFormat::Bc7Unorm => 98,
+        VkFormat::Bc7Srgb => 99,
+        VkFormat::Astc { .. } => unreachable!("DDS can't store ASTC"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_compressed_header_round_trips() {
+        let layers = vec![
+            vec![vec![1; 64], vec![2; 16]],
+            vec![vec![3; 64], vec![4; 16]],
+        ];
+        let texture = DdsTexture {
+            format: VkFormat::Bc7Srgb,
+            width: 8,
+            height: 8,
+            layers: layers.clone(),
+        };
+        let bytes = texture.to_bytes();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(u32_at(4), HEADER_LEN);
+        let flags = u32_at(8);
+        assert_eq!(
+            flags,
+            DDSD_CAPS
+                | DDSD_HEIGHT
+                | DDSD_WIDTH
+                | DDSD_PIXELFORMAT
+                | DDSD_LINEARSIZE
+                | DDSD_MIPMAPCOUNT
+        );
+        assert_eq!((u32_at(12), u32_at(16)), (8, 8));
+        assert_eq!(u32_at(20), 64);
+        assert_eq!(u32_at(28), 2);
+        assert_eq!(u32_at(76), PIXEL_FORMAT_LEN);
+        assert_eq!(u32_at(80), DDPF_FOURCC);
+        assert_eq!(&bytes[84..88], b"DX10");
+        assert_eq!(
+            u32_at(108),
+            DDSCAPS_TEXTURE | DDSCAPS_COMPLEX | DDSCAPS_MIPMAP
+        );
+
+        // The DX10 header follows the 124-byte header.
+        let dx10 = 4 + HEADER_LEN as usize;
+        assert_eq!(u32_at(dx10), 99);
+        assert_eq!(u32_at(dx10 + 4), D3D10_RESOURCE_DIMENSION_TEXTURE2D);
+        assert_eq!(u32_at(dx10 + 12), 2);
+
+        let data: Vec<u8> = layers.into_iter().flatten().flatten().collect();
+        assert_eq!(&bytes[dx10 + 20..], &data[..]);
+    }
+
+    #[test]
+    fn uncompressed_header_has_a_pitch() {
+        let texture = DdsTexture {
+            format: VkFormat::R8G8Unorm,
+            width: 3,
+            height: 2,
+            layers: vec![vec![vec![0; 12]]],
+        };
+        let bytes = texture.to_bytes();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        assert_eq!(u32_at(8) & (DDSD_PITCH | DDSD_MIPMAPCOUNT), DDSD_PITCH);
+        assert_eq!(u32_at(20), 6);
+        assert_eq!(u32_at(108), DDSCAPS_TEXTURE);
+        assert_eq!(u32_at(4 + HEADER_LEN as usize), 49);
+    }
+}
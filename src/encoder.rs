@@ -0,0 +1,417 @@
+use crate::astcenc::Astcenc;
+use crate::basisu::Basisu;
+use crate::interrupt;
+use crate::ktx2::{Ktx2Expectation, Ktx2Format, Ktx2Info, Ktx2Texture, VkFormat};
+use crate::mipmap::{generate_mips, mip_level_count};
+use crate::output;
+use crate::plan::{PlanStep, Planner};
+use crate::toktx::Toktx;
+use crate::{
+    EncodeOptions, EncoderBackend, Ktx2TextureCodec, MaterialAttribute, MipGeneration,
+    TextureFormat,
+};
+use anyhow::Context;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How often a running encoder is checked for having exited, timed out or been
+/// interrupted.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Everything an encoder needs to encode the images of one attribute into a
+/// KTX2 file.
+pub struct EncodeJob<'a> {
+    pub attribute: MaterialAttribute,
+    pub codec: Ktx2TextureCodec,
+    /// The PNG images of each layer, with one path per mip level when using
+    /// `MipGeneration::Native`, otherwise just the base level.
+    pub layer_paths: &'a [Vec<PathBuf>],
+    /// Whether to write an array texture, even with a single layer.
+    pub is_array: bool,
+    pub mips: MipGeneration,
+    pub encode: &'a EncodeOptions,
+    pub output_path: &'a Path,
+    /// Where the encoder can write its own intermediate files.
+    pub staging_directory: &'a Path,
+}
+
+impl EncodeJob<'_> {
+    pub fn num_levels(&self) -> usize {
+        self.layer_paths.first().map_or(0, Vec::len)
+    }
+
+    /// Where the encoder writes the output, which is renamed to `output_path`
+    /// once it has been written successfully.
+    pub fn partial_path(&self) -> PathBuf {
+        output::partial_path(self.output_path)
+    }
+
+    /// The file that the output of the external encoders is saved to, in the
+    /// "logs" directory next to the output.
+    pub fn log_path(&self) -> PathBuf {
+        let directory = self.output_path.parent().unwrap_or(Path::new(""));
+        let name = self.output_path.file_stem().unwrap_or_default();
+        directory.join("logs").join(name).with_extension("log")
+    }
+}
+
+/// A way of encoding PNG images into a KTX2 file.
+pub trait TextureEncoder: Sync {
+    fn supports(&self, codec: Ktx2TextureCodec) -> bool;
+
+    /// Check that the mips of `attr` can be made this way.
+    fn check_mips(&self, _attr: MaterialAttribute, _mips: MipGeneration) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Encode the job, or only record the steps it would take in a dry run.
+    fn encode(&self, job: &EncodeJob, planner: &Planner) -> anyhow::Result<()>;
+}
+
+impl EncoderBackend {
+    pub fn encoder(&self) -> &'static dyn TextureEncoder {
+        match self {
+            Self::Toktx => &Toktx,
+            Self::Basisu => &Basisu,
+            Self::Astcenc => &Astcenc,
+            Self::Uncompressed => &Uncompressed,
+        }
+    }
+}
+
+/// Check that the encoder chosen for each attribute that `texture_format`
+/// encodes can encode it with `mips`, before any images are converted.
+pub(crate) fn check_encoders(
+    encode: &EncodeOptions,
+    texture_format: TextureFormat,
+    mips: MipGeneration,
+) -> anyhow::Result<()> {
+    for attr in MaterialAttribute::ALL {
+        if let Some(codec) = texture_format.encoder_codec(attr) {
+            check_encoder(encode, attr, codec, mips)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_encoder(
+    encode: &EncodeOptions,
+    attr: MaterialAttribute,
+    codec: Ktx2TextureCodec,
+    mips: MipGeneration,
+) -> anyhow::Result<EncoderBackend> {
+    let backend = encode.encoder(attr);
+    if !backend.encoder().supports(codec) {
+        anyhow::bail!(
+            "{backend} can't encode {attr:?} as {codec:?}; pick another encoder with --encoder or \
+             --attribute-encoder"
+        );
+    }
+    backend.encoder().check_mips(attr, mips)?;
+    Ok(backend)
+}
+
+/// Where to look for an external encoder.
+pub(crate) struct Executable {
+    /// The names it is installed under, in order of preference.
+    pub names: &'static [&'static str],
+    pub flag: &'static str,
+    pub env_var: &'static str,
+    /// Where to get it, for when it can't be found.
+    pub install: &'static str,
+}
+
+/// Look for `executable` at `given`, then in its environment variable, then
+/// under each of its names on the PATH.
+pub(crate) fn find_executable(
+    executable: &Executable,
+    given: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    let Executable {
+        names,
+        flag,
+        env_var,
+        install,
+    } = executable;
+    if let Some(path) = given {
+        if !path.is_file() {
+            anyhow::bail!("{flag} {path:?} is not a file");
+        }
+        return Ok(path.to_owned());
+    }
+    if let Some(path) = std::env::var_os(env_var) {
+        let path = PathBuf::from(path);
+        if !path.is_file() {
+            anyhow::bail!(
+                "The {env_var} environment variable is set to {path:?}, which is not a file"
+            );
+        }
+        return Ok(path);
+    }
+
+    let dirs: Vec<PathBuf> = std::env::var_os("PATH")
+        .iter()
+        .flat_map(std::env::split_paths)
+        .collect();
+    names
+        .iter()
+        .flat_map(|name| {
+            let file_name = format!("{name}{}", std::env::consts::EXE_SUFFIX);
+            dirs.iter().map(move |dir| dir.join(&file_name))
+        })
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            let name = names[0];
+            let also = if names.len() > 1 {
+                format!(" (as any of {})", names.join(", "))
+            } else {
+                String::new()
+            };
+            anyhow::anyhow!(
+                "Couldn't find {name} on the PATH{also}. {install}, or give its location with \
+                 {flag} or the {env_var} environment variable"
+            )
+        })
+}
+
+/// Find `executable` if `backend` encodes any of the outputs of
+/// `texture_format`. In a dry run, not finding it is only a warning.
+pub(crate) fn find_backend(
+    backend: EncoderBackend,
+    executable: &Executable,
+    given: Option<&Path>,
+    encode: &EncodeOptions,
+    texture_format: TextureFormat,
+    planner: &Planner,
+) -> anyhow::Result<Option<PathBuf>> {
+    let used = MaterialAttribute::ALL.into_iter().any(|attr| {
+        encode.encoder(attr) == backend && texture_format.encoder_codec(attr).is_some()
+    });
+    if !used {
+        return Ok(None);
+    }
+    match find_executable(executable, given) {
+        Ok(path) => Ok(Some(path)),
+        Err(e) if planner.is_dry_run() => {
+            eprintln!("Warning: {e:#}");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Encode `job` with the encoder chosen for its attribute.
+///
+/// The output only appears at `job.output_path` once it is complete, so a
+/// failed or interrupted encoder never leaves a partial file behind.
+pub fn encode(job: &EncodeJob, planner: &Planner) -> anyhow::Result<()> {
+    let backend = check_encoder(job.encode, job.attribute, job.codec, job.mips)?;
+    if planner.is_dry_run() {
+        return backend.encoder().encode(job, planner);
+    }
+
+    interrupt::check()?;
+    // Start a fresh log for each encode. Only external encoders write one.
+    let log_path = job.log_path();
+    match std::fs::remove_file(&log_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("{log_path:?}"));
+        }
+        _ => {}
+    }
+
+    output::write_with(job.output_path, |partial_path| {
+        backend
+            .encoder()
+            .encode(job, planner)
+            .and_then(|()| check_output(job, backend, partial_path))
+    })
+}
+
+/// Check that the KTX2 file `backend` wrote to `path` for `job` has the
+/// format, transfer function, dimensions, layers and mip levels it was asked
+/// for.
+fn check_output(job: &EncodeJob, backend: EncoderBackend, path: &Path) -> anyhow::Result<()> {
+    let attr = job.attribute;
+    let srgb = attr == MaterialAttribute::Albedo;
+    let format = match (backend, job.codec) {
+        (EncoderBackend::Uncompressed, _) => Ktx2Format::Vk(VkFormat::uncompressed(attr)),
+        (_, Ktx2TextureCodec::Astc) => Ktx2Format::Vk(VkFormat::Astc {
+            block_size: job.encode.astc_block_size(attr),
+            srgb,
+        }),
+        (_, Ktx2TextureCodec::Uastc) => Ktx2Format::Uastc,
+        (_, Ktx2TextureCodec::Etc1s) => Ktx2Format::Etc1s,
+    };
+
+    let base_path = &job.layer_paths[0][0];
+    let dimensions =
+        image::image_dimensions(base_path).with_context(|| format!("{base_path:?}"))?;
+    let num_levels = match job.mips {
+        MipGeneration::Toktx => mip_level_count(dimensions),
+        MipGeneration::Native | MipGeneration::None => job.num_levels(),
+    };
+
+    let expected = Ktx2Expectation {
+        format,
+        srgb,
+        dimensions,
+        layers: job.is_array.then_some(job.layer_paths.len() as u32),
+        num_levels,
+    };
+    Ktx2Info::read(path)?
+        .check(&expected)
+        .with_context(|| format!("{backend} wrote an unexpected {:?}", job.output_path))
+}
+
+/// Writes uncompressed KTX2 in-process, in the same formats as
+/// `TextureFormat::Ktx2`, whatever the codec.
+pub struct Uncompressed;
+
+impl TextureEncoder for Uncompressed {
+    fn supports(&self, _codec: Ktx2TextureCodec) -> bool {
+        true
+    }
+
+    fn encode(&self, job: &EncodeJob, planner: &Planner) -> anyhow::Result<()> {
+        planner.record(PlanStep::WriteFile {
+            path: job.output_path.to_owned(),
+        });
+        if planner.is_dry_run() {
+            return Ok(());
+        }
+
+        let layer_levels = job
+            .layer_paths
+            .par_iter()
+            .map(|paths| {
+                let levels = paths
+                    .iter()
+                    .map(|path| image::open(path).with_context(|| format!("{path:?}")))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(match job.mips {
+                    MipGeneration::Toktx => {
+                        generate_mips(job.attribute, &levels[0], TextureFormat::Ktx2)
+                    }
+                    MipGeneration::Native | MipGeneration::None => levels,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let format = VkFormat::uncompressed(job.attribute);
+        let mut texture = Ktx2Texture::from_layer_levels(format, &layer_levels, job.is_array)?;
+        texture.zstd_level = job.encode.zstd;
+        texture.write(&job.partial_path())
+    }
+}
+
+/// Run an external encoder for `job`, or only record it in a dry run.
+///
+/// Arguments are passed as OS strings, so paths don't need to be UTF-8. The
+/// encoder's stdout and stderr are appended to the job's log file, which is
+/// created by its first command. It is killed if it runs for longer than
+/// `--encoder-timeout` or Ctrl-C is pressed.
+pub(crate) fn run_command(
+    program: &Path,
+    args: &[OsString],
+    job: &EncodeJob,
+    planner: &Planner,
+) -> anyhow::Result<()> {
+    let command_name = program.display();
+    let args_lossy: Vec<String> = args
+        .iter()
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
+    planner.record(PlanStep::RunCommand {
+        program: command_name.to_string(),
+        args: args_lossy.clone(),
+    });
+    if planner.is_dry_run() {
+        return Ok(());
+    }
+
+    eprintln!("Running {command_name} with args = {args:?}");
+
+    let log_path = job.log_path();
+    if let Some(log_directory) = log_path.parent() {
+        std::fs::create_dir_all(log_directory).with_context(|| format!("{log_directory:?}"))?;
+    }
+    let mut log = File::options()
+        .append(true)
+        .create(true)
+        .open(&log_path)
+        .with_context(|| format!("{log_path:?}"))?;
+    writeln!(log, "$ {command_name} {}", args_lossy.join(" "))?;
+    let output_start = log.metadata()?.len();
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log.try_clone()?)
+        .spawn()
+        .with_context(|| format!("Couldn't run {command_name}"))?;
+
+    let timeout = job.encode.encoder_timeout.map(Duration::from_secs);
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let timed_out = timeout.map_or(false, |timeout| started.elapsed() > timeout);
+        if timed_out || interrupt::is_interrupted() {
+            // It may have exited in the meantime.
+            let _ = child.kill();
+            child.wait()?;
+            if timed_out {
+                anyhow::bail!(
+                    "{command_name} was killed after running for more than {}s; its output is in \
+                     {log_path:?}",
+                    timeout.unwrap().as_secs()
+                );
+            }
+            anyhow::bail!("{command_name} was interrupted");
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    if !status.success() {
+        // Ctrl-C also reaches the encoder, which then fails on its own.
+        interrupt::check()?;
+
+        let mut error_str = format!("{command_name} failed");
+        if let Some(code) = status.code() {
+            error_str.push_str(&format!(" with exit code {code}"));
+        }
+        let mut output = Vec::new();
+        let mut log = File::open(&log_path).with_context(|| format!("{log_path:?}"))?;
+        log.seek(SeekFrom::Start(output_start))?;
+        log.read_to_end(&mut output)?;
+        let output = String::from_utf8_lossy(&output);
+        if !output.trim().is_empty() {
+            error_str.push_str(&format!("\nOUTPUT = {}", output.trim_end()));
+        }
+        anyhow::bail!(anyhow::anyhow!(error_str));
+    }
+
+    Ok(())
+}
+
+/// Parse an `--attribute-encoder` argument of the form
+/// `<attribute>=<encoder>`, such as `normal=astcenc`.
+pub(crate) fn parse_attribute_encoder_arg(
+    arg: &str,
+) -> Result<(MaterialAttribute, EncoderBackend), String> {
+    let (name, encoder) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected <attribute>=<encoder>, got {arg:?}"))?;
+    let attr = MaterialAttribute::parse_canonical_name(name)?;
+    let encoder = EncoderBackend::from_str(encoder, true)?;
+    Ok((attr, encoder))
+}
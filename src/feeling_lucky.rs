@@ -1,28 +1,68 @@
-use super::{convert_images, guess_input, make_array_material, MaterialFormat};
-use crate::TextureFormat;
+use super::{MaterialFormat, Planner};
+use crate::convert_images::convert_assignments;
+use crate::guess_input::guess_and_write_assignments;
+use crate::make_array_material::{check_materials_match, make_array_from_metadata};
+use crate::{
+    EncodeOptions, LayoutOptions, MetalRoughOptions, MipGeneration, MipOptions, TextureFormat,
+};
+use rayon::prelude::*;
 use std::path::{Path, PathBuf};
 
+#[allow(clippy::too_many_arguments)]
 pub fn feeling_lucky(
     input_directories: &[PathBuf],
     material_format: MaterialFormat,
     texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    metal_rough_options: &MetalRoughOptions,
+    layout: &LayoutOptions,
     output_directory: &Path,
+    planner: &Planner,
 ) -> anyhow::Result<()> {
-    let mut converted_input_dirs = Vec::new();
-    for input_dir in input_directories {
-        let guesses_path = input_dir.join("guesses").with_extension("ron");
-        guess_input(input_dir, &guesses_path)?;
-        let output_dir_path = input_dir.with_extension("converted");
-        convert_images(
-            &guesses_path,
-            material_format,
-            // Only PNG supported for intermediate conversions.
-            TextureFormat::Png,
-            &output_dir_path,
-        )?;
-        converted_input_dirs.push(output_dir_path);
-    }
-    make_array_material(&converted_input_dirs, texture_format, output_directory)?;
+    mips.validate()?;
+    layout.validate()?;
+    let encode = &encode.resolve(texture_format, mips, planner)?;
+
+    // Each material is converted independently, so they can all be done in
+    // parallel.
+    let converted = input_directories
+        .par_iter()
+        .map(|input_dir| {
+            let guesses_path = input_dir.join("guesses").with_extension("ron");
+            let guesses = guess_and_write_assignments(input_dir, &guesses_path, planner)?;
+            let output_dir_path = input_dir.with_extension("converted");
+            let metadata = convert_assignments(
+                &guesses,
+                material_format,
+                // Only PNG supported for intermediate conversions.
+                TextureFormat::Png,
+                // Mips are only needed for the final array textures.
+                &MipOptions {
+                    generation: MipGeneration::None,
+                    ..mips.clone()
+                },
+                encode,
+                metal_rough_options,
+                &output_dir_path,
+                planner,
+            )?;
+            Ok((output_dir_path, metadata))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (converted_input_dirs, metadata): (Vec<_>, Vec<_>) = converted.into_iter().unzip();
+    check_materials_match(&converted_input_dirs, &metadata)?;
+    make_array_from_metadata(
+        &converted_input_dirs,
+        &metadata[0],
+        texture_format,
+        mips,
+        encode,
+        layout,
+        output_directory,
+        planner,
+    )?;
 
     Ok(())
 }
@@ -1,4 +1,6 @@
 use super::MaterialAttribute;
+use crate::output;
+use crate::plan::{PlanStep, Planner};
 use anyhow::Context;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
@@ -18,7 +20,37 @@ const SUBSTRING_MAP: [(&str, MaterialAttribute); 12] = [
     ("emissi", MaterialAttribute::Emissive),
 ];
 
-pub fn guess_input(input_dir: &Path, output_file: &Path) -> anyhow::Result<()> {
+pub fn guess_input(input_dir: &Path, output_file: &Path, planner: &Planner) -> anyhow::Result<()> {
+    guess_and_write_assignments(input_dir, output_file, planner)?;
+
+    Ok(())
+}
+
+/// Like `guess_input`, but also returns the guesses.
+pub(crate) fn guess_and_write_assignments(
+    input_dir: &Path,
+    output_file: &Path,
+    planner: &Planner,
+) -> anyhow::Result<Vec<(MaterialAttribute, PathBuf)>> {
+    let guesses = guess_assignments(input_dir)?;
+
+    planner.record(PlanStep::GuessInput {
+        input_directory: input_dir.to_owned(),
+        assignments: guesses.clone(),
+        output: output_file.to_owned(),
+    });
+    if planner.is_dry_run() {
+        return Ok(guesses);
+    }
+
+    let s = ron::ser::to_string_pretty(&guesses, Default::default())?;
+    output::write(output_file, s)?;
+
+    Ok(guesses)
+}
+
+/// Guess the attribute of each image file in `input_dir`.
+fn guess_assignments(input_dir: &Path) -> anyhow::Result<Vec<(MaterialAttribute, PathBuf)>> {
     let mut guessed_attrs = HashSet::<MaterialAttribute>::new();
     let mut guesses = Vec::<(MaterialAttribute, PathBuf)>::new();
     for entry in std::fs::read_dir(input_dir).with_context(|| format!("{input_dir:?}"))? {
@@ -71,8 +103,5 @@ pub fn guess_input(input_dir: &Path, output_file: &Path) -> anyhow::Result<()> {
         }
     }
 
-    let s = ron::ser::to_string_pretty(&guesses, Default::default())?;
-    std::fs::write(output_file, s)?;
-
-    Ok(())
+    Ok(guesses)
 }
@@ -0,0 +1,27 @@
+use crate::ktx2::Ktx2Info;
+use std::path::PathBuf;
+
+/// Print the properties of each KTX2 file. Files that can't be read are
+/// reported, and make the whole command fail once the rest are printed.
+pub fn inspect(paths: &[PathBuf]) -> anyhow::Result<()> {
+    let mut num_failed = 0;
+    for path in paths {
+        match Ktx2Info::read(path) {
+            Ok(info) => {
+                println!("{}", path.display());
+                for line in info.to_string().lines() {
+                    println!("    {line}");
+                }
+            }
+            Err(e) => {
+                eprintln!("{e:#}");
+                num_failed += 1;
+            }
+        }
+    }
+
+    if num_failed > 0 {
+        anyhow::bail!("Couldn't read {num_failed} of {} files", paths.len());
+    }
+    Ok(())
+}
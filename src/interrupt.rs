@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Files and directories to remove if the process exits on a second Ctrl-C,
+/// which skips the usual cleanup.
+static REMOVE_ON_EXIT: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Handle Ctrl-C by asking the work in progress to stop.
+///
+/// Running encoders are killed and their partial outputs removed, and no new
+/// images are started. A second Ctrl-C removes partial outputs and temporary
+/// directories, then exits immediately.
+pub fn install_interrupt_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            for path in remove_on_exit_paths().drain(..) {
+                let _ = if path.is_dir() {
+                    std::fs::remove_dir_all(&path)
+                } else {
+                    std::fs::remove_file(&path)
+                };
+            }
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, stopping the running encoders. Press Ctrl-C again to exit now.");
+    })?;
+    Ok(())
+}
+
+pub(crate) fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Returns an error if Ctrl-C has been pressed.
+pub(crate) fn check() -> anyhow::Result<()> {
+    if is_interrupted() {
+        anyhow::bail!("Interrupted");
+    }
+    Ok(())
+}
+
+/// Remove `path`, a file or a directory, if the process exits on a second
+/// Ctrl-C.
+pub(crate) fn remove_on_exit(path: &Path) {
+    remove_on_exit_paths().push(path.to_owned());
+}
+
+/// Undo `remove_on_exit` once `path` has been cleaned up or is complete.
+pub(crate) fn keep_on_exit(path: &Path) {
+    let mut paths = remove_on_exit_paths();
+    if let Some(index) = paths.iter().rposition(|p| p == path) {
+        paths.swap_remove(index);
+    }
+}
+
+fn remove_on_exit_paths() -> MutexGuard<'static, Vec<PathBuf>> {
+    REMOVE_ON_EXIT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,1222 @@
+use super::{AstcBlockSize, BcMetalRough, EncodeOptions, MaterialAttribute, TextureFormat};
+use crate::bcn::{self, BcFormat};
+use anyhow::Context;
+use image::{DynamicImage, ImageBuffer};
+use std::path::Path;
+
+const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const HEADER_LEN: usize = 80;
+const LEVEL_INDEX_ENTRY_LEN: usize = 24;
+
+const KTX_SS_NONE: u32 = 0;
+const KTX_SS_BASIS_LZ: u32 = 1;
+const KTX_SS_ZSTD: u32 = 2;
+
+const KHR_DF_MODEL_ETC1S: u8 = 163;
+const KHR_DF_MODEL_UASTC: u8 = 166;
+const KHR_DF_TRANSFER_LINEAR: u8 = 1;
+const KHR_DF_TRANSFER_SRGB: u8 = 2;
+
+/// The 2D block sizes that ASTC supports, in the order of their `VkFormat`
+/// values.
+pub(crate) const ASTC_BLOCK_SIZES: [(u8, u8); 14] = [
+    (4, 4),
+    (5, 4),
+    (5, 5),
+    (6, 5),
+    (6, 6),
+    (8, 5),
+    (8, 6),
+    (8, 8),
+    (10, 5),
+    (10, 6),
+    (10, 8),
+    (10, 10),
+    (12, 10),
+    (12, 12),
+];
+
+/// The formats the native writer supports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VkFormat {
+    R8Unorm,
+    R8G8Unorm,
+    R8G8B8A8Unorm,
+    R8G8B8A8Srgb,
+    R16Unorm,
+    Bc1RgbUnorm,
+    Bc4Unorm,
+    Bc5Unorm,
+    Bc7Unorm,
+    Bc7Srgb,
+    /// ASTC blocks, which are encoded by "astcenc" rather than in-process.
+    Astc {
+        block_size: AstcBlockSize,
+        srgb: bool,
+    },
+}
+
+impl VkFormat {
+    const NON_ASTC: [Self; 10] = [
+        Self::R8Unorm,
+        Self::R8G8Unorm,
+        Self::R8G8B8A8Unorm,
+        Self::R8G8B8A8Srgb,
+        Self::R16Unorm,
+        Self::Bc1RgbUnorm,
+        Self::Bc4Unorm,
+        Self::Bc5Unorm,
+        Self::Bc7Unorm,
+        Self::Bc7Srgb,
+    ];
+
+    /// The format with the `VkFormat` enum value `value`, if it is one we
+    /// write.
+    pub fn from_value(value: u32) -> Option<Self> {
+        if let Some(format) = Self::NON_ASTC.into_iter().find(|f| f.value() == value) {
+            return Some(format);
+        }
+        let index = value.checked_sub(157)?;
+        let &dimensions = ASTC_BLOCK_SIZES.get(index as usize / 2)?;
+        Some(Self::Astc {
+            block_size: AstcBlockSize::try_from(dimensions).ok()?,
+            srgb: index % 2 == 1,
+        })
+    }
+
+    /// The `VkFormat` enum value.
+    pub fn value(&self) -> u32 {
+        match self {
+            Self::R8Unorm => 9,
+            Self::R8G8Unorm => 16,
+            Self::R8G8B8A8Unorm => 37,
+            Self::R8G8B8A8Srgb => 43,
+            Self::R16Unorm => 70,
+            Self::Bc1RgbUnorm => 131,
+            Self::Bc4Unorm => 139,
+            Self::Bc5Unorm => 141,
+            Self::Bc7Unorm => 145,
+            Self::Bc7Srgb => 146,
+            Self::Astc { block_size, srgb } => {
+                let index = ASTC_BLOCK_SIZES
+                    .iter()
+                    .position(|&size| size == block_size.dimensions())
+                    .unwrap() as u32;
+                157 + 2 * index + *srgb as u32
+            }
+        }
+    }
+
+    /// The format used for `attr` when writing `texture_format`, if it is
+    /// written natively rather than by "toktx". DDS files use the same
+    /// formats as KTX2 files.
+    pub fn for_texture_format(
+        texture_format: TextureFormat,
+        attr: MaterialAttribute,
+        encode: &EncodeOptions,
+    ) -> Option<Self> {
+        match texture_format {
+            TextureFormat::Ktx2 | TextureFormat::Dds => Some(Self::uncompressed(attr)),
+            TextureFormat::Ktx2Bc | TextureFormat::DdsBc => {
+                Some(Self::block_compressed(attr, encode))
+            }
+            TextureFormat::Ktx2Astc
+            | TextureFormat::Ktx2Uastc
+            | TextureFormat::Ktx2Etc1s
+            | TextureFormat::Png
+            | TextureFormat::Tga
+            | TextureFormat::Webp
+            | TextureFormat::Exr
+            | TextureFormat::Tiff => None,
+        }
+    }
+
+    /// The format used for `attr` in uncompressed files.
+    pub(crate) fn uncompressed(attr: MaterialAttribute) -> Self {
+        match attr {
+            MaterialAttribute::Albedo => Self::R8G8B8A8Srgb,
+            MaterialAttribute::AmbientOcclusion
+            | MaterialAttribute::Emissive
+            | MaterialAttribute::Metallic
+            | MaterialAttribute::Roughness => Self::R8Unorm,
+            MaterialAttribute::Depth => Self::R16Unorm,
+            // Only the green and blue channels are used, but there is no
+            // 2-channel format that skips red.
+            MaterialAttribute::MetallicRoughness => Self::R8G8B8A8Unorm,
+            // Bevy reconstructs Z for 2-channel normal maps.
+            MaterialAttribute::Normal => Self::R8G8Unorm,
+        }
+    }
+
+    /// The format used for `attr` in block-compressed files.
+    fn block_compressed(attr: MaterialAttribute, encode: &EncodeOptions) -> Self {
+        match attr {
+            MaterialAttribute::Albedo => Self::Bc7Srgb,
+            MaterialAttribute::AmbientOcclusion
+            | MaterialAttribute::Depth
+            | MaterialAttribute::Emissive
+            | MaterialAttribute::Metallic
+            | MaterialAttribute::Roughness => Self::Bc4Unorm,
+            MaterialAttribute::MetallicRoughness => match encode.bc_metal_rough {
+                BcMetalRough::Bc7 => Self::Bc7Unorm,
+                BcMetalRough::Bc1 => Self::Bc1RgbUnorm,
+            },
+            MaterialAttribute::Normal => Self::Bc5Unorm,
+        }
+    }
+
+    pub(crate) fn block_compression(&self) -> Option<BcFormat> {
+        match self {
+            Self::Bc1RgbUnorm => Some(BcFormat::Bc1),
+            Self::Bc4Unorm => Some(BcFormat::Bc4),
+            Self::Bc5Unorm => Some(BcFormat::Bc5),
+            Self::Bc7Unorm | Self::Bc7Srgb => Some(BcFormat::Bc7),
+            _ => None,
+        }
+    }
+
+    /// The size of the type that needs endian conversion, which is 1 for
+    /// block-compressed formats.
+    fn type_size(&self) -> u32 {
+        match self {
+            Self::R16Unorm => 2,
+            _ => 1,
+        }
+    }
+
+    /// The size in bytes of a texel, or a block for block-compressed formats.
+    pub(crate) fn block_bytes(&self) -> u32 {
+        match self.block_compression() {
+            Some(bc) => bc.block_bytes() as u32,
+            None if self.is_astc() => 16,
+            None => self.num_channels() * self.type_size(),
+        }
+    }
+
+    /// The width and height of a block in texels, which is 1x1 for
+    /// uncompressed formats.
+    fn block_dimensions(&self) -> (u8, u8) {
+        match self {
+            Self::Astc { block_size, .. } => block_size.dimensions(),
+            _ if self.block_compression().is_some() => (4, 4),
+            _ => (1, 1),
+        }
+    }
+
+    /// Whether `Ktx2Texture::image` can decode this format, which is only true
+    /// of uncompressed formats.
+    pub(crate) fn can_decode(&self) -> bool {
+        self.block_dimensions() == (1, 1)
+    }
+
+    /// The size in bytes of `num_layers` layers of a mip level with the given
+    /// dimensions.
+    fn level_len(&self, (width, height): (u32, u32), num_layers: u32) -> u64 {
+        let (block_width, block_height) = self.block_dimensions();
+        let blocks_wide = width.div_ceil(block_width as u32) as u64;
+        let blocks_high = height.div_ceil(block_height as u32) as u64;
+        blocks_wide * blocks_high * self.block_bytes() as u64 * num_layers as u64
+    }
+
+    fn is_astc(&self) -> bool {
+        matches!(self, Self::Astc { .. })
+    }
+
+    fn num_channels(&self) -> u32 {
+        match self {
+            Self::R8Unorm | Self::R16Unorm | Self::Bc4Unorm => 1,
+            Self::R8G8Unorm | Self::Bc5Unorm => 2,
+            Self::Bc1RgbUnorm => 3,
+            Self::R8G8B8A8Unorm
+            | Self::R8G8B8A8Srgb
+            | Self::Bc7Unorm
+            | Self::Bc7Srgb
+            | Self::Astc { .. } => 4,
+        }
+    }
+
+    fn is_srgb(&self) -> bool {
+        matches!(
+            self,
+            Self::R8G8B8A8Srgb | Self::Bc7Srgb | Self::Astc { srgb: true, .. }
+        )
+    }
+
+    /// The tightly packed texel data of `img` in this format.
+    pub fn image_bytes(&self, img: &DynamicImage) -> Vec<u8> {
+        if let Some(bc) = self.block_compression() {
+            return bcn::compress(bc, img);
+        }
+        match self {
+            Self::R8Unorm => img.to_luma8().into_raw(),
+            Self::R8G8Unorm => img
+                .to_rgb8()
+                .pixels()
+                .flat_map(|p| [p.0[0], p.0[1]])
+                .collect(),
+            Self::R8G8B8A8Unorm | Self::R8G8B8A8Srgb => img.to_rgba8().into_raw(),
+            Self::R16Unorm => img
+                .to_luma16()
+                .pixels()
+                .flat_map(|p| p.0[0].to_le_bytes())
+                .collect(),
+            _ => unreachable!("{self:?} is block-compressed"),
+        }
+    }
+
+    /// The inverse of `image_bytes` for uncompressed formats. The red and
+    /// green channels of R8G8 textures are normal X and Y, so Z is
+    /// reconstructed into the blue channel.
+    fn decode_image(&self, width: u32, height: u32, data: &[u8]) -> Option<DynamicImage> {
+        let img = match self {
+            Self::R8Unorm => {
+                DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data.to_vec())?)
+            }
+            Self::R8G8Unorm => {
+                let texels = data.chunks_exact(2).flat_map(|xy| {
+                    let [x, y] = [xy[0], xy[1]].map(|c| c as f32 / 127.5 - 1.0);
+                    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+                    [xy[0], xy[1], ((z + 1.0) * 127.5).round() as u8]
+                });
+                DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, texels.collect())?)
+            }
+            Self::R8G8B8A8Unorm | Self::R8G8B8A8Srgb => {
+                DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data.to_vec())?)
+            }
+            Self::R16Unorm => {
+                let texels = data
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]));
+                DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, texels.collect())?)
+            }
+            _ => return None,
+        };
+        Some(img)
+    }
+}
+
+impl std::fmt::Display for VkFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::R8Unorm => write!(f, "R8_UNORM"),
+            Self::R8G8Unorm => write!(f, "R8G8_UNORM"),
+            Self::R8G8B8A8Unorm => write!(f, "R8G8B8A8_UNORM"),
+            Self::R8G8B8A8Srgb => write!(f, "R8G8B8A8_SRGB"),
+            Self::R16Unorm => write!(f, "R16_UNORM"),
+            Self::Bc1RgbUnorm => write!(f, "BC1_RGB_UNORM_BLOCK"),
+            Self::Bc4Unorm => write!(f, "BC4_UNORM_BLOCK"),
+            Self::Bc5Unorm => write!(f, "BC5_UNORM_BLOCK"),
+            Self::Bc7Unorm => write!(f, "BC7_UNORM_BLOCK"),
+            Self::Bc7Srgb => write!(f, "BC7_SRGB_BLOCK"),
+            Self::Astc { block_size, srgb } => {
+                let encoding = if *srgb { "SRGB" } else { "UNORM" };
+                write!(f, "ASTC_{block_size}_{encoding}_BLOCK")
+            }
+        }
+    }
+}
+
+/// How the texels of a KTX2 file are encoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ktx2Format {
+    Vk(VkFormat),
+    /// Basis Universal UASTC, with an undefined `VkFormat`.
+    Uastc,
+    /// Basis Universal ETC1S, with an undefined `VkFormat`.
+    Etc1s,
+    /// Anything we don't write.
+    Other {
+        vk_format: u32,
+        color_model: u8,
+    },
+}
+
+impl std::fmt::Display for Ktx2Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Vk(format) => write!(f, "{format}"),
+            Self::Uastc => write!(f, "UASTC"),
+            Self::Etc1s => write!(f, "ETC1S"),
+            Self::Other {
+                vk_format,
+                color_model,
+            } => write!(f, "VkFormat {vk_format} (color model {color_model})"),
+        }
+    }
+}
+
+/// The properties of a KTX2 file, as read from its header, level index, data
+/// format descriptor and key/value data.
+#[derive(Debug)]
+pub struct Ktx2Info {
+    pub format: Ktx2Format,
+    /// Whether the data format descriptor has the sRGB transfer function.
+    pub srgb: bool,
+    pub width: u32,
+    pub height: u32,
+    /// `None` for a plain 2D texture, otherwise the number of array layers.
+    pub layers: Option<u32>,
+    pub faces: u32,
+    pub supercompression_scheme: u32,
+    /// The stored and uncompressed byte length of each mip level, starting
+    /// with the base level.
+    pub level_lengths: Vec<(u64, u64)>,
+    pub key_values: Vec<(String, String)>,
+}
+
+impl Ktx2Info {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("{path:?}"))?;
+        Self::parse(&bytes).with_context(|| format!("{path:?} is not a valid KTX2 file"))
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < HEADER_LEN || bytes[..IDENTIFIER.len()] != IDENTIFIER {
+            anyhow::bail!("Missing the KTX2 identifier");
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let vk_format = u32_at(12);
+        let width = u32_at(20);
+        let height = u32_at(24);
+        let layer_count = u32_at(32);
+        let faces = u32_at(36);
+        let level_count = u32_at(40);
+        let supercompression_scheme = u32_at(44);
+        let dfd_offset = u32_at(48) as usize;
+        let dfd_len = u32_at(52) as usize;
+        let kvd_offset = u32_at(56) as usize;
+        let kvd_len = u32_at(60) as usize;
+
+        let level_lengths = level_index(bytes, level_count)?
+            .into_iter()
+            .map(|(_, length, uncompressed_length)| (length, uncompressed_length))
+            .collect();
+
+        // The descriptor block follows the total size of the descriptor.
+        const BLOCK_HEADER_LEN: usize = 4 + 24;
+        if dfd_len < BLOCK_HEADER_LEN || dfd_offset.saturating_add(dfd_len) > bytes.len() {
+            anyhow::bail!("The data format descriptor is missing or truncated");
+        }
+        let color_model = bytes[dfd_offset + 12];
+        let transfer_function = bytes[dfd_offset + 14];
+
+        if kvd_offset.saturating_add(kvd_len) > bytes.len() {
+            anyhow::bail!("The key/value data is truncated");
+        }
+        let key_values = parse_key_values(&bytes[kvd_offset..kvd_offset + kvd_len]);
+
+        let format = match (VkFormat::from_value(vk_format), color_model) {
+            (Some(format), _) => Ktx2Format::Vk(format),
+            (None, KHR_DF_MODEL_UASTC) if vk_format == 0 => Ktx2Format::Uastc,
+            (None, KHR_DF_MODEL_ETC1S) if vk_format == 0 => Ktx2Format::Etc1s,
+            (None, _) => Ktx2Format::Other {
+                vk_format,
+                color_model,
+            },
+        };
+
+        Ok(Self {
+            format,
+            srgb: transfer_function == KHR_DF_TRANSFER_SRGB,
+            width,
+            height,
+            layers: (layer_count > 0).then_some(layer_count),
+            faces,
+            supercompression_scheme,
+            level_lengths,
+            key_values,
+        })
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.level_lengths.len()
+    }
+
+    /// Check that the file has the given properties, listing every one that
+    /// doesn't match.
+    pub fn check(&self, expected: &Ktx2Expectation) -> anyhow::Result<()> {
+        let mut mismatches = Vec::new();
+        if self.format != expected.format {
+            mismatches.push(format!(
+                "format is {}, expected {}",
+                self.format, expected.format
+            ));
+        }
+        if self.srgb != expected.srgb {
+            mismatches.push(format!(
+                "transfer function is {}, expected {}",
+                transfer_name(self.srgb),
+                transfer_name(expected.srgb)
+            ));
+        }
+        if (self.width, self.height) != expected.dimensions {
+            mismatches.push(format!(
+                "dimensions are {}x{}, expected {}x{}",
+                self.width, self.height, expected.dimensions.0, expected.dimensions.1
+            ));
+        }
+        if self.layers != expected.layers {
+            mismatches.push(format!(
+                "layers are {}, expected {}",
+                layers_name(self.layers),
+                layers_name(expected.layers)
+            ));
+        }
+        if self.num_levels() != expected.num_levels {
+            mismatches.push(format!(
+                "has {} mip levels, expected {}",
+                self.num_levels(),
+                expected.num_levels
+            ));
+        }
+        if self.faces != 1 {
+            mismatches.push(format!("has {} faces, expected 1", self.faces));
+        }
+        if !mismatches.is_empty() {
+            anyhow::bail!(
+                "The KTX2 file doesn't match what was asked for:\n    {}",
+                mismatches.join("\n    ")
+            );
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Ktx2Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let supercompression = match self.supercompression_scheme {
+            KTX_SS_NONE => "none".to_owned(),
+            KTX_SS_BASIS_LZ => "BasisLZ".to_owned(),
+            KTX_SS_ZSTD => "Zstandard".to_owned(),
+            scheme => format!("unknown ({scheme})"),
+        };
+        writeln!(f, "format: {}", self.format)?;
+        writeln!(f, "transfer function: {}", transfer_name(self.srgb))?;
+        writeln!(f, "dimensions: {}x{}", self.width, self.height)?;
+        writeln!(f, "layers: {}", layers_name(self.layers))?;
+        if self.faces != 1 {
+            writeln!(f, "faces: {}", self.faces)?;
+        }
+        writeln!(f, "mip levels: {}", self.num_levels())?;
+        writeln!(f, "supercompression: {supercompression}")?;
+        for (key, value) in &self.key_values {
+            writeln!(f, "{key}: {value}")?;
+        }
+        for (level, (stored, uncompressed)) in self.level_lengths.iter().enumerate() {
+            let (width, height) = crate::mipmap::mip_dimensions((self.width, self.height), level);
+            write!(f, "level {level}: {width}x{height}, {stored} bytes")?;
+            if stored != uncompressed && *uncompressed != 0 {
+                write!(f, " ({uncompressed} uncompressed)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// The offset, stored length and uncompressed length of each mip level,
+/// checking that every level is within `bytes`.
+fn level_index(bytes: &[u8], level_count: u32) -> anyhow::Result<Vec<(u64, u64, u64)>> {
+    let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    // A level count of 0 asks the loader to generate the mips, but only the
+    // base level is stored.
+    let num_levels = level_count.max(1) as usize;
+    let index_end = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * num_levels;
+    if bytes.len() < index_end {
+        anyhow::bail!("The level index is truncated");
+    }
+    (0..num_levels)
+        .map(|level| {
+            let entry = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * level;
+            let (offset, length) = (u64_at(entry), u64_at(entry + 8));
+            if offset.saturating_add(length) > bytes.len() as u64 {
+                anyhow::bail!("Mip level {level} is truncated");
+            }
+            Ok((offset, length, u64_at(entry + 16)))
+        })
+        .collect()
+}
+
+/// What an encoder was asked to write, to check its output against.
+pub struct Ktx2Expectation {
+    pub format: Ktx2Format,
+    pub srgb: bool,
+    pub dimensions: (u32, u32),
+    pub layers: Option<u32>,
+    pub num_levels: usize,
+}
+
+fn transfer_name(srgb: bool) -> &'static str {
+    if srgb {
+        "sRGB"
+    } else {
+        "linear"
+    }
+}
+
+fn layers_name(layers: Option<u32>) -> String {
+    match layers {
+        Some(layers) => layers.to_string(),
+        None => "none (not an array)".to_owned(),
+    }
+}
+
+/// Parse key/value data, skipping any entry that isn't a null-terminated UTF-8
+/// key followed by a UTF-8 value.
+fn parse_key_values(mut kvd: &[u8]) -> Vec<(String, String)> {
+    let mut key_values = Vec::new();
+    while kvd.len() >= 4 {
+        let len = u32::from_le_bytes(kvd[..4].try_into().unwrap()) as usize;
+        let Some(entry) = kvd.get(4..4 + len) else {
+            break;
+        };
+        if let Some(split) = entry.iter().position(|&b| b == 0) {
+            let value = &entry[split + 1..];
+            let value = value.strip_suffix(&[0]).unwrap_or(value);
+            if let (Ok(key), Ok(value)) = (
+                std::str::from_utf8(&entry[..split]),
+                std::str::from_utf8(value),
+            ) {
+                key_values.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        kvd = kvd.get(align(4 + len, 4)..).unwrap_or_default();
+    }
+    key_values
+}
+
+/// A 2D texture or 2D array texture with a full or partial mip chain.
+pub struct Ktx2Texture {
+    pub format: VkFormat,
+    pub width: u32,
+    pub height: u32,
+    /// `None` for a plain 2D texture, otherwise the number of array layers.
+    pub layers: Option<u32>,
+    /// The texel data of each mip level, starting with the base level, with
+    /// the layers of each level concatenated.
+    pub levels: Vec<Vec<u8>>,
+    /// If set, each mip level is supercompressed with Zstandard at this
+    /// level.
+    pub zstd_level: Option<u8>,
+}
+
+impl Ktx2Texture {
+    /// Build a texture from the mip levels of each layer.
+    pub fn from_layer_levels(
+        format: VkFormat,
+        layer_levels: &[Vec<DynamicImage>],
+        is_array: bool,
+    ) -> anyhow::Result<Self> {
+        let Some(first_layer) = layer_levels.first() else {
+            anyhow::bail!("Can't make a KTX2 texture with no layers");
+        };
+        let Some(base) = first_layer.first() else {
+            anyhow::bail!("Can't make a KTX2 texture with no mip levels");
+        };
+        let num_levels = first_layer.len();
+        if layer_levels.iter().any(|levels| levels.len() != num_levels) {
+            anyhow::bail!("All layers of a KTX2 texture need the same number of mip levels");
+        }
+
+        let levels = (0..num_levels)
+            .map(|level| {
+                layer_levels
+                    .iter()
+                    .flat_map(|levels| format.image_bytes(&levels[level]))
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            format,
+            width: base.width(),
+            height: base.height(),
+            layers: is_array.then_some(layer_levels.len() as u32),
+            levels,
+            zstd_level: None,
+        })
+    }
+
+    /// Read a texture in one of the formats we write, undoing any Zstandard
+    /// supercompression.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("{path:?}"))?;
+        Self::parse(&bytes).with_context(|| format!("Couldn't read {path:?}"))
+    }
+
+    fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let info = Ktx2Info::parse(bytes)?;
+        let Ktx2Format::Vk(format) = info.format else {
+            anyhow::bail!("{} textures can't be read", info.format);
+        };
+        if info.faces != 1 {
+            anyhow::bail!("Cube maps can't be read");
+        }
+
+        let level_count = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        let num_layers = info.layers.unwrap_or(1).max(1);
+        let levels = level_index(bytes, level_count)?
+            .into_iter()
+            .enumerate()
+            .map(|(level, (offset, length, uncompressed_length))| {
+                // Check the index against the format before trusting it with
+                // an allocation size.
+                let dimensions = crate::mipmap::mip_dimensions((info.width, info.height), level);
+                let expected_length = format.level_len(dimensions, num_layers);
+                let data = &bytes[offset as usize..(offset + length) as usize];
+                match info.supercompression_scheme {
+                    KTX_SS_NONE if length != expected_length => {
+                        anyhow::bail!("Mip level {level} is {length} bytes, not {expected_length}")
+                    }
+                    KTX_SS_NONE => Ok(data.to_vec()),
+                    KTX_SS_ZSTD if uncompressed_length != expected_length => anyhow::bail!(
+                        "Mip level {level} should decompress to {expected_length} bytes, \
+                         but the level index says {uncompressed_length}"
+                    ),
+                    KTX_SS_ZSTD => {
+                        let level_data = zstd::bulk::decompress(data, expected_length as usize)?;
+                        if level_data.len() as u64 != expected_length {
+                            anyhow::bail!("Mip level {level} decompressed to the wrong size");
+                        }
+                        Ok(level_data)
+                    }
+                    scheme => anyhow::bail!("Supercompression scheme {scheme} isn't supported"),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            format,
+            width: info.width,
+            height: info.height,
+            layers: info.layers,
+            levels,
+            zstd_level: None,
+        })
+    }
+
+    /// Decode a layer of a mip level, which only works for uncompressed
+    /// formats.
+    pub fn image(&self, level: usize, layer: usize) -> anyhow::Result<DynamicImage> {
+        let (width, height) = crate::mipmap::mip_dimensions((self.width, self.height), level);
+        let num_layers = self.layers.unwrap_or(1).max(1) as usize;
+        let data = self
+            .levels
+            .get(level)
+            .filter(|_| layer < num_layers)
+            .and_then(|data| {
+                let layer_len = data.len() / num_layers;
+                data.get(layer * layer_len..(layer + 1) * layer_len)
+            })
+            .with_context(|| format!("There's no layer {layer} of mip level {level}"))?;
+        self.format
+            .decode_image(width, height, data)
+            .with_context(|| {
+                format!(
+                    "{} textures can't be decoded, only copied into arrays of the same format",
+                    self.format
+                )
+            })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let bytes = self.to_bytes().with_context(|| format!("{path:?}"))?;
+        std::fs::write(path, bytes).with_context(|| format!("{path:?}"))
+    }
+
+    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let num_levels = self.levels.len();
+        let dfd = self.data_format_descriptor();
+        let kvd = key_value_data();
+
+        let (supercompression_scheme, stored_levels) = match self.zstd_level {
+            None => (KTX_SS_NONE, self.levels.clone()),
+            Some(level) => {
+                let compressed = self
+                    .levels
+                    .iter()
+                    .map(|data| zstd::bulk::compress(data, level as i32))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                (KTX_SS_ZSTD, compressed)
+            }
+        };
+
+        let dfd_offset = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * num_levels;
+        let kvd_offset = dfd_offset + dfd.len();
+        // Each level has to start on a multiple of both 4 and the block size,
+        // unless it is supercompressed.
+        let level_alignment = match self.format.block_bytes() {
+            _ if self.zstd_level.is_some() => 1,
+            bytes if bytes % 4 == 0 => bytes as usize,
+            _ => 4,
+        };
+        let mut data_offset = align(kvd_offset + kvd.len(), level_alignment);
+
+        // Mip levels are stored smallest first, so the file can be streamed.
+        let mut level_offsets = vec![0; num_levels];
+        for (level, data) in stored_levels.iter().enumerate().rev() {
+            level_offsets[level] = data_offset;
+            data_offset = align(data_offset + data.len(), level_alignment);
+        }
+
+        let mut out = Vec::with_capacity(data_offset);
+        out.extend_from_slice(&IDENTIFIER);
+        for value in [
+            self.format.value(),
+            self.format.type_size(),
+            self.width,
+            self.height,
+            0, // pixelDepth
+            self.layers.unwrap_or(0),
+            1, // faceCount
+            num_levels as u32,
+            supercompression_scheme,
+            dfd_offset as u32,
+            dfd.len() as u32,
+            kvd_offset as u32,
+            kvd.len() as u32,
+        ] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        // No supercompression global data.
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+
+        for ((data, stored), offset) in self.levels.iter().zip(&stored_levels).zip(&level_offsets) {
+            for value in [*offset, stored.len(), data.len()] {
+                out.extend_from_slice(&(value as u64).to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&dfd);
+        out.extend_from_slice(&kvd);
+
+        for level in (0..num_levels).rev() {
+            out.resize(level_offsets[level], 0);
+            out.extend_from_slice(&stored_levels[level]);
+        }
+
+        Ok(out)
+    }
+
+    /// A Khronos Data Format basic descriptor block for the texture's format.
+    fn data_format_descriptor(&self) -> Vec<u8> {
+        const KHR_DF_MODEL_RGBSDA: u8 = 1;
+        const KHR_DF_MODEL_BC1A: u8 = 128;
+        const KHR_DF_MODEL_BC4: u8 = 131;
+        const KHR_DF_MODEL_BC5: u8 = 132;
+        const KHR_DF_MODEL_BC7: u8 = 134;
+        const KHR_DF_MODEL_ASTC: u8 = 162;
+        const KHR_DF_PRIMARIES_BT709: u8 = 1;
+        const KHR_DF_SAMPLE_DATATYPE_LINEAR: u8 = 0x10;
+        const RGBA_CHANNEL_IDS: [u8; 4] = [0, 1, 2, 15];
+
+        let format = self.format;
+        let (color_model, samples): (u8, Vec<Sample>) = match format.block_compression() {
+            None if format.is_astc() => {
+                let sample = Sample {
+                    bit_offset: 0,
+                    bit_length: 128,
+                    channel_type: 0,
+                    upper: u32::MAX,
+                };
+                (KHR_DF_MODEL_ASTC, vec![sample])
+            }
+            None => {
+                let bits = 8 * format.type_size();
+                let upper = (1u64 << bits) as u32 - 1;
+                let samples = RGBA_CHANNEL_IDS
+                    .iter()
+                    .take(format.num_channels() as usize)
+                    .enumerate()
+                    .map(|(i, &channel_id)| {
+                        let mut channel_type = channel_id;
+                        // The alpha channel of an sRGB format is still
+                        // linear.
+                        if format.is_srgb() && channel_id == 15 {
+                            channel_type |= KHR_DF_SAMPLE_DATATYPE_LINEAR;
+                        }
+                        Sample {
+                            bit_offset: i as u32 * bits,
+                            bit_length: bits,
+                            channel_type,
+                            upper,
+                        }
+                    })
+                    .collect();
+                (KHR_DF_MODEL_RGBSDA, samples)
+            }
+            Some(bc) => {
+                let color_model = match bc {
+                    BcFormat::Bc1 => KHR_DF_MODEL_BC1A,
+                    BcFormat::Bc4 => KHR_DF_MODEL_BC4,
+                    BcFormat::Bc5 => KHR_DF_MODEL_BC5,
+                    BcFormat::Bc7 => KHR_DF_MODEL_BC7,
+                };
+                // BC5 has a red and a green BC4 block, every other format
+                // is one sample of the whole block.
+                let block_sample = |bit_offset, bit_length, channel_type| Sample {
+                    bit_offset,
+                    bit_length,
+                    channel_type,
+                    upper: u32::MAX,
+                };
+                let samples = match bc {
+                    BcFormat::Bc5 => vec![block_sample(0, 64, 0), block_sample(64, 64, 1)],
+                    _ => vec![block_sample(0, 8 * bc.block_bytes() as u32, 0)],
+                };
+                (color_model, samples)
+            }
+        };
+        let block_size = 24 + 16 * samples.len();
+
+        let mut block = Vec::with_capacity(block_size);
+        // vendorId = Khronos, descriptorType = basic
+        block.extend_from_slice(&0u32.to_le_bytes());
+        // versionNumber = 1.3
+        block.extend_from_slice(&2u16.to_le_bytes());
+        block.extend_from_slice(&(block_size as u16).to_le_bytes());
+        block.push(color_model);
+        block.push(KHR_DF_PRIMARIES_BT709);
+        block.push(if format.is_srgb() {
+            KHR_DF_TRANSFER_SRGB
+        } else {
+            KHR_DF_TRANSFER_LINEAR
+        });
+        // flags: straight alpha
+        block.push(0);
+        // texelBlockDimension, stored as one less than the size
+        let (block_width, block_height) = format.block_dimensions();
+        block.extend_from_slice(&[block_width - 1, block_height - 1, 0, 0]);
+        // Supercompressed data is unsized.
+        let mut bytes_plane = [0; 8];
+        if self.zstd_level.is_none() {
+            bytes_plane[0] = format.block_bytes() as u8;
+        }
+        block.extend_from_slice(&bytes_plane);
+
+        for sample in samples {
+            block.extend_from_slice(&(sample.bit_offset as u16).to_le_bytes());
+            block.push((sample.bit_length - 1) as u8);
+            block.push(sample.channel_type);
+            block.extend_from_slice(&[0; 4]); // samplePosition
+            block.extend_from_slice(&0u32.to_le_bytes()); // sampleLower
+            block.extend_from_slice(&sample.upper.to_le_bytes());
+        }
+
+        let mut dfd = Vec::with_capacity(4 + block.len());
+        dfd.extend_from_slice(&(4 + block.len() as u32).to_le_bytes());
+        dfd.extend_from_slice(&block);
+        dfd
+    }
+}
+
+/// A sample of a descriptor block, which is a channel of a texel or block.
+struct Sample {
+    bit_offset: u32,
+    bit_length: u32,
+    channel_type: u8,
+    upper: u32,
+}
+
+const KEY_VALUES: &[(&str, &str)] = &[(
+    "KTXwriter",
+    concat!("material-converter ", env!("CARGO_PKG_VERSION")),
+)];
+
+fn key_value_data() -> Vec<u8> {
+    let mut kvd = Vec::new();
+    for (key, value) in KEY_VALUES {
+        let len = key.len() + 1 + value.len() + 1;
+        kvd.extend_from_slice(&(len as u32).to_le_bytes());
+        kvd.extend_from_slice(key.as_bytes());
+        kvd.push(0);
+        kvd.extend_from_slice(value.as_bytes());
+        kvd.push(0);
+        kvd.resize(align(kvd.len(), 4), 0);
+    }
+    kvd
+}
+
+fn align(offset: usize, alignment: usize) -> usize {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    fn gradient(width: u32, height: u32, seed: u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([x as u8 * 16, y as u8 * 16, seed, 255 - seed])
+        }))
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> usize {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+    }
+
+    #[test]
+    fn round_trip() {
+        let layer_levels: Vec<Vec<DynamicImage>> = (0..2)
+            .map(|layer| vec![gradient(8, 4, layer), gradient(4, 2, layer)])
+            .collect();
+        for zstd_level in [None, Some(3)] {
+            let mut texture =
+                Ktx2Texture::from_layer_levels(VkFormat::R8G8B8A8Srgb, &layer_levels, true)
+                    .unwrap();
+            texture.zstd_level = zstd_level;
+            for (level, data) in texture.levels.iter().enumerate() {
+                let expected: Vec<u8> = layer_levels
+                    .iter()
+                    .flat_map(|levels| levels[level].to_rgba8().into_raw())
+                    .collect();
+                assert_eq!(*data, expected);
+            }
+            let bytes = texture.to_bytes().unwrap();
+
+            let info = Ktx2Info::parse(&bytes).unwrap();
+            assert_eq!(info.format, Ktx2Format::Vk(VkFormat::R8G8B8A8Srgb));
+            assert!(info.srgb);
+            assert_eq!((info.width, info.height), (8, 4));
+            assert_eq!(info.layers, Some(2));
+            assert_eq!(info.faces, 1);
+            let scheme = if zstd_level.is_some() {
+                KTX_SS_ZSTD
+            } else {
+                KTX_SS_NONE
+            };
+            assert_eq!(info.supercompression_scheme, scheme);
+            assert_eq!(info.num_levels(), 2);
+            for ((stored, uncompressed), level) in info.level_lengths.iter().zip(&texture.levels) {
+                assert_eq!(*uncompressed, level.len() as u64);
+                if zstd_level.is_none() {
+                    assert_eq!(*stored, level.len() as u64);
+                }
+            }
+            assert_eq!(
+                info.key_values,
+                vec![(
+                    "KTXwriter".to_owned(),
+                    concat!("material-converter ", env!("CARGO_PKG_VERSION")).to_owned()
+                )]
+            );
+
+            assert_eq!(bytes[..12], IDENTIFIER);
+            let header: Vec<u32> = (0..13).map(|i| u32_at(&bytes, 12 + 4 * i)).collect();
+            let dfd = texture.data_format_descriptor();
+            let kvd = key_value_data();
+            let dfd_offset = HEADER_LEN + 2 * LEVEL_INDEX_ENTRY_LEN;
+            let kvd_offset = dfd_offset + dfd.len();
+            assert_eq!(
+                header,
+                [
+                    VkFormat::R8G8B8A8Srgb.value(),
+                    1,
+                    8,
+                    4,
+                    0,
+                    2,
+                    1,
+                    2,
+                    scheme,
+                    dfd_offset as u32,
+                    dfd.len() as u32,
+                    kvd_offset as u32,
+                    kvd.len() as u32,
+                ]
+            );
+            assert_eq!((u64_at(&bytes, 64), u64_at(&bytes, 72)), (0, 0));
+            assert_eq!(bytes[dfd_offset..kvd_offset], dfd);
+            assert_eq!(bytes[kvd_offset..kvd_offset + kvd.len()], kvd);
+
+            let mut offsets = Vec::new();
+            for (level, data) in texture.levels.iter().enumerate() {
+                let entry = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * level;
+                let (offset, length) = (u64_at(&bytes, entry), u64_at(&bytes, entry + 8));
+                assert_eq!(u64_at(&bytes, entry + 16), data.len());
+                let stored = &bytes[offset..offset + length];
+                if zstd_level.is_some() {
+                    assert_eq!(zstd::bulk::decompress(stored, data.len()).unwrap(), *data);
+                } else {
+                    assert_eq!(offset % 4, 0);
+                    assert_eq!(stored, data);
+                }
+                offsets.push((offset, length));
+            }
+            // The smallest level comes first.
+            assert!(offsets[1].0 < offsets[0].0);
+            assert_eq!(bytes.len(), offsets[0].0 + offsets[0].1);
+
+            let read = Ktx2Texture::parse(&bytes).unwrap();
+            assert_eq!(read.format, texture.format);
+            assert_eq!((read.width, read.height), (8, 4));
+            assert_eq!(read.layers, Some(2));
+            assert_eq!(read.levels, texture.levels);
+            assert_eq!(
+                read.image(1, 1).unwrap().to_rgba8(),
+                layer_levels[1][1].to_rgba8()
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_block_compressed() {
+        let texture = Ktx2Texture {
+            format: VkFormat::Bc7Unorm,
+            width: 8,
+            height: 8,
+            layers: None,
+            levels: vec![(0..64).collect(), (64..80).collect()],
+            zstd_level: None,
+        };
+        let bytes = texture.to_bytes().unwrap();
+        assert_eq!(u32_at(&bytes, 12), VkFormat::Bc7Unorm.value());
+        // typeSize is 1 for block-compressed formats.
+        assert_eq!(u32_at(&bytes, 16), 1);
+        for (level, data) in texture.levels.iter().enumerate() {
+            let entry = HEADER_LEN + LEVEL_INDEX_ENTRY_LEN * level;
+            let offset = u64_at(&bytes, entry);
+            assert_eq!(offset % 16, 0);
+            assert_eq!(bytes[offset..offset + data.len()], *data);
+        }
+        let read = Ktx2Texture::parse(&bytes).unwrap();
+        assert_eq!(read.format, VkFormat::Bc7Unorm);
+        assert_eq!(read.layers, None);
+        assert_eq!(read.levels, texture.levels);
+        // Block-compressed levels can't be decoded.
+        assert!(read.image(0, 0).is_err());
+    }
+
+    #[test]
+    fn key_values_are_null_terminated_and_padded() {
+        let kvd = key_value_data();
+        let value = concat!("material-converter ", env!("CARGO_PKG_VERSION"));
+        let len = u32_at(&kvd, 0) as usize;
+        assert_eq!(len, "KTXwriter".len() + 1 + value.len() + 1);
+        assert_eq!(kvd[4..4 + len], *format!("KTXwriter\0{value}\0").as_bytes());
+        assert_eq!(kvd.len(), align(4 + len, 4));
+    }
+
+    #[test]
+    fn data_format_descriptor() {
+        let dfd_of = |format, zstd_level| {
+            Ktx2Texture {
+                format,
+                width: 4,
+                height: 4,
+                layers: None,
+                levels: vec![Vec::new()],
+                zstd_level,
+            }
+            .data_format_descriptor()
+        };
+
+        // RGBA8 sRGB: 4 samples, and the alpha sample is flagged linear.
+        let dfd = dfd_of(VkFormat::R8G8B8A8Srgb, None);
+        assert_eq!(u32_at(&dfd, 0) as usize, dfd.len());
+        assert_eq!(dfd.len(), 4 + 24 + 16 * 4);
+        assert_eq!(u32_at(&dfd, 8) >> 16, 24 + 16 * 4);
+        assert_eq!(dfd[12], 1); // RGBSDA
+        assert_eq!(dfd[13], 1); // BT.709
+        assert_eq!(dfd[14], 2); // sRGB
+        assert_eq!(&dfd[16..20], &[0, 0, 0, 0]);
+        assert_eq!(dfd[20], 4);
+        for (i, channel_type) in [0, 1, 2, 0x1F].into_iter().enumerate() {
+            let sample = 28 + 16 * i;
+            assert_eq!(
+                u16::from_le_bytes([dfd[sample], dfd[sample + 1]]),
+                8 * i as u16
+            );
+            assert_eq!(dfd[sample + 2], 7);
+            assert_eq!(dfd[sample + 3], channel_type);
+            assert_eq!(u32_at(&dfd, sample + 12), 255);
+        }
+
+        // R16: one linear sample, and no bytes per plane once supercompressed.
+        let dfd = dfd_of(VkFormat::R16Unorm, None);
+        assert_eq!(dfd.len(), 4 + 24 + 16);
+        assert_eq!(dfd[14], 1); // linear
+        assert_eq!(dfd[20], 2);
+        assert_eq!(dfd[28 + 2], 15);
+        assert_eq!(dfd[28 + 3], 0);
+        assert_eq!(u32_at(&dfd, 28 + 12), 65535);
+        assert_eq!(dfd_of(VkFormat::R16Unorm, Some(3))[20], 0);
+
+        // BC7 sRGB: one 128-bit sample over a 4x4 block.
+        let dfd = dfd_of(VkFormat::Bc7Srgb, None);
+        assert_eq!(dfd.len(), 4 + 24 + 16);
+        assert_eq!(dfd[12], 134); // BC7
+        assert_eq!(dfd[14], 2); // sRGB
+        assert_eq!(&dfd[16..20], &[3, 3, 0, 0]);
+        assert_eq!(dfd[20], 16);
+        assert_eq!(dfd[28 + 2], 127);
+
+        // ASTC 6x6: one 128-bit sample over a 6x6 block.
+        let block_size = AstcBlockSize::try_from((6, 6)).unwrap();
+        let dfd = dfd_of(
+            VkFormat::Astc {
+                block_size,
+                srgb: false,
+            },
+            None,
+        );
+        assert_eq!(dfd[12], 162); // ASTC
+        assert_eq!(dfd[14], 1); // linear
+        assert_eq!(&dfd[16..18], &[5, 5]);
+        assert_eq!(dfd[20], 16);
+        assert_eq!(dfd[28 + 2], 127);
+    }
+
+    #[test]
+    fn vk_format_values() {
+        for format in VkFormat::NON_ASTC {
+            assert_eq!(VkFormat::from_value(format.value()), Some(format));
+        }
+        for &dimensions in &ASTC_BLOCK_SIZES {
+            for srgb in [false, true] {
+                let format = VkFormat::Astc {
+                    block_size: AstcBlockSize::try_from(dimensions).unwrap(),
+                    srgb,
+                };
+                assert_eq!(VkFormat::from_value(format.value()), Some(format));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let texture =
+            Ktx2Texture::from_layer_levels(VkFormat::R8Unorm, &[vec![gradient(4, 4, 0)]], false)
+                .unwrap();
+        let bytes = texture.to_bytes().unwrap();
+        assert!(Ktx2Info::parse(&bytes).is_ok());
+        assert!(Ktx2Info::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Ktx2Info::parse(&bytes[..HEADER_LEN]).is_err());
+        assert!(Ktx2Info::parse(&[0; HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn level_lengths_are_checked_against_the_format() {
+        let mut texture =
+            Ktx2Texture::from_layer_levels(VkFormat::R8Unorm, &[vec![gradient(4, 4, 0)]], false)
+                .unwrap();
+        texture.zstd_level = Some(3);
+        let mut bytes = texture.to_bytes().unwrap();
+        assert!(Ktx2Texture::parse(&bytes).is_ok());
+
+        // A huge uncompressed length mustn't be used to size the output.
+        let entry = HEADER_LEN + 16;
+        bytes[entry..entry + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Ktx2Texture::parse(&bytes).is_err());
+        bytes[entry..entry + 8].copy_from_slice(&15u64.to_le_bytes());
+        assert!(Ktx2Texture::parse(&bytes).is_err());
+    }
+}
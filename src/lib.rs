@@ -1,17 +1,42 @@
+mod astc;
+mod astcenc;
+mod atlas;
+mod basisu;
+mod bcn;
+mod cache;
 mod convert_images;
+mod dds;
+mod encoder;
 mod feeling_lucky;
 mod guess_input;
+mod inspect;
+mod interrupt;
+mod ktx2;
 mod make_array_material;
+mod metadata;
+mod mipmap;
+mod output;
+mod plan;
+mod raster;
+mod registry;
+mod staging;
 mod toktx;
 
+pub use astc::{AstcBlockSize, AstcProfile, AstcQuality};
 pub use convert_images::convert_images;
 pub use feeling_lucky::feeling_lucky;
 pub use guess_input::guess_input;
+pub use inspect::inspect;
+pub use interrupt::install_interrupt_handler;
 pub use make_array_material::make_array_material;
+pub use metadata::{Adjustment, ArrayMaterial, ArrayMetadata, MaterialMetadata, UvRect};
+pub use plan::{BuildPlan, DryRunOptions, PlanFormat, PlanStep, Planner};
+pub use registry::{LayerRegistry, RegisteredLayer};
 
-use clap::ValueEnum;
+use clap::{Args, ValueEnum};
 use image::DynamicImage;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
 pub enum MaterialFormat {
@@ -37,12 +62,590 @@ impl std::fmt::Display for MaterialFormat {
 
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
 pub enum TextureFormat {
+    /// KTX2 encoded as ASTC, by "toktx" unless another `--encoder` is given.
     Ktx2Astc,
+    /// Uncompressed KTX2, written in-process without "toktx".
+    ///
+    /// - albedo: RGBA8 (sRGB)
+    /// - ambient occlusion, emissive: R8
+    /// - depth: R16
+    /// - metallic_roughness: RGBA8
+    /// - normal: RG8, with Z reconstructed from X and Y
+    Ktx2,
+    /// Block-compressed KTX2 for desktop GPUs, encoded in-process.
+    ///
+    /// - albedo: BC7 (sRGB)
+    /// - ambient occlusion, depth, emissive: BC4
+    /// - metallic_roughness: BC7
+    /// - normal: BC5, with Z reconstructed from X and Y
+    Ktx2Bc,
+    /// KTX2 encoded as UASTC, which can be transcoded to the native formats
+    /// of both desktop and web GPUs.
+    Ktx2Uastc,
+    /// KTX2 encoded as ETC1S, which transcodes like UASTC but is much
+    /// smaller. Normals are still encoded as UASTC, since ETC1S can't keep X
+    /// and Y apart.
+    Ktx2Etc1s,
+    /// Uncompressed DDS with a DX10 header, written in-process in the same
+    /// formats as `ktx2`.
+    Dds,
+    /// Block-compressed DDS with a DX10 header, written in-process in the
+    /// same formats as `ktx2-bc`.
+    DdsBc,
     Png,
+    /// 8-bit TGA, for older tools.
+    Tga,
+    /// Lossless WebP, which is usually smaller than PNG.
+    Webp,
+    /// 32-bit float OpenEXR. Every attribute is stored as RGB, or RGBA for
+    /// albedo, with single-channel maps repeated in each channel.
+    Exr,
+    /// TIFF, with 16-bit depth maps and 8-bit everything else.
+    Tiff,
+}
+
+impl TextureFormat {
+    /// Whether this is a KTX2 or DDS format written in-process rather than by
+    /// a `TextureEncoder`.
+    pub(crate) fn is_native(&self) -> bool {
+        matches!(self, Self::Ktx2 | Self::Ktx2Bc | Self::Dds | Self::DdsBc)
+    }
+
+    pub(crate) fn is_dds(&self) -> bool {
+        matches!(self, Self::Dds | Self::DdsBc)
+    }
+
+    /// The extension of the output files.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::Ktx2Astc | Self::Ktx2 | Self::Ktx2Bc | Self::Ktx2Uastc | Self::Ktx2Etc1s => {
+                "ktx2"
+            }
+            Self::Dds | Self::DdsBc => "dds",
+            Self::Png => "png",
+            Self::Tga => "tga",
+            Self::Webp => "webp",
+            Self::Exr => "exr",
+            Self::Tiff => "tiff",
+        }
+    }
+
+    /// Whether each mip level is written to its own plain image file.
+    pub(crate) fn is_raster(&self) -> bool {
+        matches!(
+            self,
+            Self::Png | Self::Tga | Self::Webp | Self::Exr | Self::Tiff
+        )
+    }
+
+    /// Convert `img` for `attr`, at the bit depth this format stores `attr`
+    /// at.
+    pub(crate) fn convert_image(
+        &self,
+        attr: MaterialAttribute,
+        img: &DynamicImage,
+    ) -> DynamicImage {
+        match (self, attr) {
+            (Self::Tiff, MaterialAttribute::Depth) => DynamicImage::ImageLuma16(img.to_luma16()),
+            (Self::Exr, MaterialAttribute::Albedo) => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+            (Self::Exr, _) => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+            _ => attr.convert_image(img),
+        }
+    }
+
+    /// The codec `attr` is encoded with by a `TextureEncoder`, if this format
+    /// isn't written in-process.
+    pub(crate) fn encoder_codec(&self, attr: MaterialAttribute) -> Option<Ktx2TextureCodec> {
+        match self {
+            Self::Ktx2Astc => Some(Ktx2TextureCodec::Astc),
+            Self::Ktx2Uastc => Some(Ktx2TextureCodec::Uastc),
+            Self::Ktx2Etc1s => match attr {
+                MaterialAttribute::Normal => Some(Ktx2TextureCodec::Uastc),
+                _ => Some(Ktx2TextureCodec::Etc1s),
+            },
+            Self::Ktx2
+            | Self::Ktx2Bc
+            | Self::Dds
+            | Self::DdsBc
+            | Self::Png
+            | Self::Tga
+            | Self::Webp
+            | Self::Exr
+            | Self::Tiff => None,
+        }
+    }
+}
+
+/// How to combine the metallic and roughness source images into a single
+/// metal_rough image.
+#[derive(Args, Clone, Debug)]
+pub struct MetalRoughOptions {
+    /// Which size to resample to when the metallic and roughness images have
+    /// different sizes.
+    #[arg(long, default_value_t = ResamplePolicy::Larger)]
+    pub resample: ResamplePolicy,
+    /// Fail instead of resampling when the metallic and roughness images have
+    /// different sizes.
+    #[arg(long)]
+    pub strict: bool,
+    /// The metallic value in [0, 1] to use when there is a roughness image but
+    /// no metallic image.
+    #[arg(long, default_value_t = 0.0)]
+    pub metallic_fill: f32,
+    /// The roughness value in [0, 1] to use when there is a metallic image but
+    /// no roughness image.
+    #[arg(long, default_value_t = 0.5)]
+    pub roughness_fill: f32,
+}
+
+impl MetalRoughOptions {
+    /// The settings that affect the metal_rough image, for the build cache.
+    /// The fill values only matter for a missing image, and the resample
+    /// policy only when the images have different sizes. `--strict` never
+    /// changes the output, only whether there is one.
+    pub(crate) fn cache_settings(
+        &self,
+        has_metallic: bool,
+        has_roughness: bool,
+        sizes_match: bool,
+    ) -> String {
+        let resample = (has_metallic && has_roughness && !sizes_match).then_some(self.resample);
+        let metallic_fill = (!has_metallic).then_some(self.metallic_fill);
+        let roughness_fill = (!has_roughness).then_some(self.roughness_fill);
+        format!("{:?}", (resample, metallic_fill, roughness_fill))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum ResamplePolicy {
+    /// Upsample the smaller image to the size of the larger one.
+    Larger,
+    /// Downsample the larger image to the size of the smaller one.
+    Smaller,
+}
+
+impl std::fmt::Display for ResamplePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Larger => write!(f, "larger"),
+            Self::Smaller => write!(f, "smaller"),
+        }
+    }
 }
 
+/// Settings for the texture encoders.
+#[derive(Args, Clone, Debug)]
+pub struct EncodeOptions {
+    /// The codec for metal_rough images with `--texture-format ktx2-bc` or
+    /// `dds-bc`.
+    #[arg(long, default_value_t = BcMetalRough::Bc7)]
+    pub bc_metal_rough: BcMetalRough,
+    /// The UASTC quality level, from 0 (fastest) to 4 (best).
+    #[arg(long, default_value_t = 2)]
+    pub uastc_quality: u8,
+    /// The rate-distortion optimization lambda for UASTC, where higher values
+    /// trade quality for better supercompression. 0 disables RDO.
+    #[arg(long, default_value_t = 1.0)]
+    pub uastc_rdo_lambda: f32,
+    /// The ETC1S compression level, from 0 (fastest) to 5 (smallest).
+    #[arg(long, default_value_t = 1)]
+    pub etc1s_compression: u8,
+    /// The ETC1S quality level, from 1 to 255.
+    #[arg(long, default_value_t = 128)]
+    pub etc1s_quality: u8,
+    /// A RON file of `AstcProfile` settings. Settings given on the command
+    /// line take precedence over the profile.
+    #[arg(long)]
+    pub astc_profile: Option<PathBuf>,
+    /// The ASTC block size of an attribute, as `<attribute>=<width>x<height>`,
+    /// such as `normal=4x4`. Can be given once for each attribute. Defaults to
+    /// 4x4 for normals, 6x6 for albedo and metal_rough, and 8x8 otherwise.
+    #[arg(long = "astc-block-size", value_parser = astc::parse_block_size_arg)]
+    pub astc_block_sizes: Vec<(MaterialAttribute, AstcBlockSize)>,
+    /// How hard the ASTC encoder searches for the best encoding. Defaults to
+    /// medium.
+    #[arg(long)]
+    pub astc_quality: Option<AstcQuality>,
+    /// The "toktx" executable to run. Defaults to the TOKTX environment
+    /// variable, then "toktx" on the PATH.
+    #[arg(long)]
+    pub toktx_path: Option<PathBuf>,
+    /// Use "toktx" even if its version can't be read from
+    /// `toktx --version`, instead of failing before any image work.
+    #[arg(long)]
+    pub skip_toktx_version_check: bool,
+    /// The "astcenc" executable to run. Defaults to the ASTCENC environment
+    /// variable, then "astcenc" on the PATH under any of the names of Arm's
+    /// releases, such as "astcenc-avx2".
+    #[arg(long)]
+    pub astcenc_path: Option<PathBuf>,
+    /// The "basisu" executable to run. Defaults to the BASISU environment
+    /// variable, then "basisu" on the PATH.
+    #[arg(long)]
+    pub basisu_path: Option<PathBuf>,
+    /// The encoder for KTX2 outputs that aren't written in-process.
+    #[arg(long, default_value_t = EncoderBackend::Toktx)]
+    pub encoder: EncoderBackend,
+    /// The encoder of an attribute, as `<attribute>=<encoder>`, such as
+    /// `normal=astcenc`. Overrides `--encoder` for that attribute.
+    #[arg(long = "attribute-encoder", value_parser = encoder::parse_attribute_encoder_arg)]
+    pub attribute_encoders: Vec<(MaterialAttribute, EncoderBackend)>,
+    /// Supercompress each mip level of KTX2 outputs with Zstandard at this
+    /// level, from 1 (fastest) to 22 (smallest). ETC1S output is already
+    /// supercompressed with BasisLZ, so it is left as is. DDS outputs are
+    /// never supercompressed.
+    #[arg(long)]
+    pub zstd: Option<u8>,
+    /// Keep the images given to the encoders in an "intermediates" directory
+    /// of the output directory, instead of a temporary directory that is
+    /// removed once they are encoded.
+    #[arg(long)]
+    pub keep_intermediates: bool,
+    /// Kill an external encoder that runs for longer than this many seconds.
+    #[arg(long)]
+    pub encoder_timeout: Option<u64>,
+}
+
+impl EncodeOptions {
+    /// Check the options and merge in the ASTC profile, if there is one.
+    ///
+    /// The encoders of the outputs of `texture_format` are checked against
+    /// `mips` and found before any images are converted, and the version of
+    /// "toktx" is checked if it is one of them.
+    pub(crate) fn resolve(
+        &self,
+        texture_format: TextureFormat,
+        mips: &MipOptions,
+        planner: &Planner,
+    ) -> anyhow::Result<Self> {
+        self.validate()?;
+
+        let mut resolved = self.clone();
+        if let Some(profile_path) = &self.astc_profile {
+            let profile = AstcProfile::load(profile_path)?;
+            let mut astc_block_sizes = profile.block_sizes;
+            astc_block_sizes.extend(self.astc_block_sizes.iter().copied());
+            resolved = Self {
+                astc_profile: None,
+                astc_block_sizes,
+                astc_quality: self.astc_quality.or(profile.quality),
+                ..resolved
+            };
+        }
+        encoder::check_encoders(&resolved, texture_format, mips.generation)?;
+        if let Some(toktx_path) = toktx::prepare(&resolved, texture_format, mips, planner)? {
+            resolved.toktx_path = Some(toktx_path);
+        }
+        if let Some(astcenc_path) = astcenc::prepare(&resolved, texture_format, planner)? {
+            resolved.astcenc_path = Some(astcenc_path);
+        }
+        if let Some(basisu_path) = basisu::prepare(&resolved, texture_format, planner)? {
+            resolved.basisu_path = Some(basisu_path);
+        }
+        Ok(resolved)
+    }
+
+    pub(crate) fn astc_block_size(&self, attr: MaterialAttribute) -> AstcBlockSize {
+        // Later settings override earlier ones.
+        self.astc_block_sizes
+            .iter()
+            .rev()
+            .find_map(|(a, size)| (*a == attr).then_some(*size))
+            .unwrap_or_else(|| AstcBlockSize::default_for(attr))
+    }
+
+    pub(crate) fn astc_quality(&self) -> AstcQuality {
+        self.astc_quality.unwrap_or(AstcQuality::Medium)
+    }
+
+    /// The settings that affect the output of `attr` in `texture_format`, for
+    /// the build cache. Where the encoder is and how long it may run don't.
+    pub(crate) fn cache_settings(
+        &self,
+        attr: MaterialAttribute,
+        texture_format: TextureFormat,
+    ) -> String {
+        match texture_format.encoder_codec(attr) {
+            None => {
+                let format = ktx2::VkFormat::for_texture_format(texture_format, attr, self);
+                // Only KTX2 files are supercompressed.
+                let zstd = self
+                    .zstd
+                    .filter(|_| format.is_some() && !texture_format.is_dds());
+                format!("{:?}", (format, zstd))
+            }
+            Some(codec) => {
+                let codec_settings = match codec {
+                    Ktx2TextureCodec::Astc => {
+                        format!("{} {:?}", self.astc_block_size(attr), self.astc_quality())
+                    }
+                    Ktx2TextureCodec::Uastc => {
+                        format!("{} {}", self.uastc_quality, self.uastc_rdo_lambda)
+                    }
+                    Ktx2TextureCodec::Etc1s => {
+                        format!("{} {}", self.etc1s_compression, self.etc1s_quality)
+                    }
+                };
+                format!(
+                    "{:?}",
+                    (self.encoder(attr), codec, codec_settings, self.zstd)
+                )
+            }
+        }
+    }
+
+    pub(crate) fn encoder(&self, attr: MaterialAttribute) -> EncoderBackend {
+        // Later settings override earlier ones.
+        self.attribute_encoders
+            .iter()
+            .rev()
+            .find_map(|(a, encoder)| (*a == attr).then_some(*encoder))
+            .unwrap_or(self.encoder)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.uastc_quality > 4 {
+            anyhow::bail!(
+                "--uastc-quality {} is not in the range [0, 4]",
+                self.uastc_quality
+            );
+        }
+        if self.uastc_rdo_lambda < 0.0 {
+            anyhow::bail!("--uastc-rdo-lambda {} is negative", self.uastc_rdo_lambda);
+        }
+        if self.etc1s_compression > 5 {
+            anyhow::bail!(
+                "--etc1s-compression {} is not in the range [0, 5]",
+                self.etc1s_compression
+            );
+        }
+        if self.etc1s_quality == 0 {
+            anyhow::bail!("--etc1s-quality must be at least 1");
+        }
+        if let Some(level) = self.zstd {
+            if !(1..=22).contains(&level) {
+                anyhow::bail!("--zstd {level} is not in the range [1, 22]");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The implementations of `TextureEncoder`.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum EncoderBackend {
+    /// The "toktx" tool from KTX-Software, which encodes every codec.
+    Toktx,
+    /// The "basisu" tool from the Basis Universal project, which encodes UASTC
+    /// and ETC1S. It can't be given pre-generated mips.
+    Basisu,
+    /// The "astcenc" tool from Arm, which encodes ASTC. It can't generate
+    /// mips, so it needs `--mips native` for mipmapped outputs.
+    Astcenc,
+    /// Writes uncompressed KTX2 in-process whatever the codec, for testing
+    /// without any encoder installed.
+    Uncompressed,
+}
+
+impl std::fmt::Display for EncoderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Toktx => write!(f, "toktx"),
+            Self::Basisu => write!(f, "basisu"),
+            Self::Astcenc => write!(f, "astcenc"),
+            Self::Uncompressed => write!(f, "uncompressed"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum BcMetalRough {
+    /// Keeps metallic and roughness apart, at 1 byte per texel.
+    Bc7,
+    /// Half the size of BC7, but metallic and roughness share one color line
+    /// per block, so they bleed into each other.
+    Bc1,
+}
+
+impl std::fmt::Display for BcMetalRough {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bc7 => write!(f, "bc7"),
+            Self::Bc1 => write!(f, "bc1"),
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct MipOptions {
+    /// How to generate mips.
+    #[arg(long = "mips", default_value_t = MipGeneration::Toktx)]
+    pub generation: MipGeneration,
+    /// Reduce specular aliasing by adding the variance of the normal map to
+    /// the roughness of each metal_rough mip. Requires `--mips native`.
+    #[arg(long)]
+    pub specular_aa: bool,
+    /// How much of the normal variance to add to the roughness.
+    #[arg(long, default_value_t = 1.0)]
+    pub specular_aa_strength: f32,
+    /// Preserve the alpha-tested coverage of albedo mips, for cutouts that
+    /// discard texels with alpha at or below this value. Requires
+    /// `--mips native`.
+    #[arg(long)]
+    pub alpha_cutoff: Option<f32>,
+}
+
+impl MipOptions {
+    /// The number of mip levels generated in-process for an image with base
+    /// level `dimensions`.
+    pub(crate) fn level_count(
+        &self,
+        texture_format: TextureFormat,
+        dimensions: (u32, u32),
+    ) -> usize {
+        match self.generation {
+            MipGeneration::Native => mipmap::mip_level_count(dimensions),
+            MipGeneration::Toktx if texture_format.is_native() => {
+                mipmap::mip_level_count(dimensions)
+            }
+            MipGeneration::Toktx | MipGeneration::None => 1,
+        }
+    }
+
+    /// The settings that affect the mips of `attr`, for the build cache.
+    pub(crate) fn cache_settings(&self, attr: MaterialAttribute) -> String {
+        let specular_aa = (attr == MaterialAttribute::MetallicRoughness && self.specular_aa)
+            .then_some(self.specular_aa_strength);
+        let alpha_cutoff = self
+            .alpha_cutoff
+            .filter(|_| attr == MaterialAttribute::Albedo);
+        format!("{:?}", (self.generation, specular_aa, alpha_cutoff))
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.specular_aa && self.generation != MipGeneration::Native {
+            anyhow::bail!("--specular-aa requires --mips native");
+        }
+        if let Some(cutoff) = self.alpha_cutoff {
+            if self.generation != MipGeneration::Native {
+                anyhow::bail!("--alpha-cutoff requires --mips native");
+            }
+            if !(0.0..1.0).contains(&cutoff) {
+                anyhow::bail!("--alpha-cutoff {cutoff} is not in the range [0, 1)");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum MipGeneration {
+    /// Let the encoder generate the mips of the KTX2 outputs it encodes. KTX2
+    /// and DDS outputs written in-process get native mips, and other outputs
+    /// get no mips.
+    Toktx,
+    /// Generate the mips of all outputs in-process, filtered appropriately for
+    /// each attribute. The mips of a PNG, TGA, WebP, EXR or TIFF output are
+    /// written next to it as "<name>.mip<level>.<extension>".
+    Native,
+    /// No mips.
+    None,
+}
+
+impl std::fmt::Display for MipGeneration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Toktx => write!(f, "toktx"),
+            Self::Native => write!(f, "native"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// How `make-array-material` arranges the materials in each output texture.
+#[derive(Args, Clone, Debug)]
+pub struct LayoutOptions {
+    /// How to arrange the materials.
+    #[arg(long, default_value_t = ArrayLayout::Stack)]
+    pub layout: ArrayLayout,
+    /// The number of columns of a grid atlas. Defaults to enough to make the
+    /// grid about square.
+    #[arg(long)]
+    pub atlas_columns: Option<u32>,
+    /// The width in texels of the border around each cell of a grid atlas. It
+    /// is filled by wrapping the cell around, so that the material still tiles
+    /// when filtered.
+    #[arg(long, default_value_t = 0)]
+    pub atlas_gutter: u32,
+    /// The most materials to put in one texture. Any more are split across
+    /// several textures, in "part<N>" subdirectories of the output directory.
+    #[arg(long)]
+    pub max_layers: Option<u32>,
+    /// The largest width or height of a texture. Stacked images and atlases
+    /// are split into parts, like with `--max-layers`, to stay within it.
+    #[arg(long, default_value_t = 16384)]
+    pub max_dimension: u32,
+    /// Drop the layers of materials that were removed since the last run
+    /// instead of keeping placeholders in their place. The materials after
+    /// them move to lower layers.
+    #[arg(long)]
+    pub compact_layers: bool,
+}
+
+impl LayoutOptions {
+    /// The settings that affect the textures of one part, for the build cache.
+    /// Which materials go in the part is covered by its layers.
+    pub(crate) fn cache_settings(&self) -> String {
+        match self.layout {
+            ArrayLayout::Stack => format!("{:?}", self.layout),
+            ArrayLayout::Grid => {
+                format!("{:?}", (self.layout, self.atlas_columns, self.atlas_gutter))
+            }
+        }
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if self.layout != ArrayLayout::Grid {
+            if self.atlas_columns.is_some() {
+                anyhow::bail!("--atlas-columns requires --layout grid");
+            }
+            if self.atlas_gutter > 0 {
+                anyhow::bail!("--atlas-gutter requires --layout grid");
+            }
+        }
+        if self.atlas_columns == Some(0) {
+            anyhow::bail!("--atlas-columns must be at least 1");
+        }
+        if self.max_layers == Some(0) {
+            anyhow::bail!("--max-layers must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Deserialize, Serialize, ValueEnum)]
+pub enum ArrayLayout {
+    /// An array texture with one layer per material. Outputs that can't hold
+    /// arrays get the layers stacked vertically instead.
+    Stack,
+    /// A 2D atlas with the materials laid out in a grid, row by row.
+    Grid,
+}
+
+impl std::fmt::Display for ArrayLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stack => write!(f, "stack"),
+            Self::Grid => write!(f, "grid"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Ktx2TextureCodec {
     Astc,
+    Uastc,
+    Etc1s,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Hash, Eq, PartialEq, Serialize)]
@@ -58,6 +661,31 @@ pub enum MaterialAttribute {
 }
 
 impl MaterialAttribute {
+    const ALL: [Self; 8] = [
+        Self::Albedo,
+        Self::AmbientOcclusion,
+        Self::Depth,
+        Self::Emissive,
+        Self::Metallic,
+        Self::MetallicRoughness,
+        Self::Normal,
+        Self::Roughness,
+    ];
+
+    /// Parse a canonical name given on the command line.
+    fn parse_canonical_name(name: &str) -> Result<Self, String> {
+        Self::ALL
+            .into_iter()
+            .find(|attr| attr.canonical_name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(Self::canonical_name).collect();
+                format!(
+                    "unknown attribute {name:?}, expected one of {}",
+                    names.join(", ")
+                )
+            })
+    }
+
     fn canonical_name(&self) -> &str {
         match self {
             Self::AmbientOcclusion => "ao",
@@ -71,18 +699,6 @@ impl MaterialAttribute {
         }
     }
 
-    fn new_image(&self, w: u32, h: u32) -> DynamicImage {
-        match self {
-            Self::AmbientOcclusion
-            | Self::Depth
-            | Self::Emissive
-            | Self::Metallic
-            | Self::Roughness => DynamicImage::new_luma8(w, h),
-            Self::MetallicRoughness | Self::Normal => DynamicImage::new_rgb8(w, h),
-            Self::Albedo => DynamicImage::new_rgba8(w, h),
-        }
-    }
-
     fn convert_image(&self, img: &DynamicImage) -> DynamicImage {
         match self {
             Self::Albedo => DynamicImage::ImageRgba8(img.to_rgba8()),
@@ -1,6 +1,8 @@
 use clap::Parser;
 use material_converter::{
-    convert_images, feeling_lucky, guess_input, make_array_material, MaterialFormat, TextureFormat,
+    convert_images, feeling_lucky, guess_input, inspect, install_interrupt_handler,
+    make_array_material, DryRunOptions, EncodeOptions, LayoutOptions, MaterialFormat,
+    MetalRoughOptions, MipOptions, Planner, TextureFormat,
 };
 use std::path::PathBuf;
 
@@ -26,6 +28,8 @@ enum Args {
         /// containing the assignment guesses.
         #[arg(short, long)]
         output: Option<PathBuf>,
+        #[command(flatten)]
+        dry_run: DryRunOptions,
     },
     /// Convert images to the desired format.
     ///
@@ -42,9 +46,21 @@ enum Args {
         /// The desired output texture format.
         #[arg(short, long)]
         texture_format: TextureFormat,
+        #[command(flatten)]
+        mips: MipOptions,
+        #[command(flatten)]
+        encode: EncodeOptions,
+        #[command(flatten)]
+        metal_rough: MetalRoughOptions,
+        /// The maximum number of images or encoder processes to work on at
+        /// once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
         /// The output directory. Will be created if it does not exist.
         #[arg(short, long)]
         output: PathBuf,
+        #[command(flatten)]
+        dry_run: DryRunOptions,
     },
     /// Combine multiple materials into an array material.
     ///
@@ -53,23 +69,39 @@ enum Args {
     /// vertically for use as an array texture (sampler2DArray in GLSL). Assumes
     /// that images of the same attribute also have the same file name (as
     /// ensured by the convert-images command).
+    ///
+    /// Each material keeps its layer across runs, as recorded in "layers.ron"
+    /// in the output directory, however the input directories are ordered.
     MakeArrayMaterial {
         /// The input directories.
-        #[arg(short, long)]
+        #[arg(short, long, required = true)]
         input: Vec<PathBuf>,
         /// The desired output texture format.
         #[arg(short, long)]
         texture_format: TextureFormat,
+        #[command(flatten)]
+        mips: MipOptions,
+        #[command(flatten)]
+        encode: EncodeOptions,
+        #[command(flatten)]
+        layout: LayoutOptions,
+        /// The maximum number of images or encoder processes to work on at
+        /// once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
         /// The output directory. Will be created if it does not exist.
         #[arg(short, long)]
         output: PathBuf,
+        #[command(flatten)]
+        dry_run: DryRunOptions,
     },
     /// guess-input, convert-images, then make-array-material
     ///
-    /// Assumes all input materials will be compatible (same size and set of
-    /// attributes).
+    /// All input materials must be compatible (same size and set of
+    /// attributes), which is checked before the array is made.
     FeelingLucky {
         /// The input directories.
+        #[arg(required = true)]
         input: Vec<PathBuf>,
         /// The desired output material format.
         #[arg(short, long, default_value_t = MaterialFormat::BevyPbr)]
@@ -77,47 +109,133 @@ enum Args {
         /// The desired output texture format.
         #[arg(short, long)]
         texture_format: TextureFormat,
+        #[command(flatten)]
+        mips: MipOptions,
+        #[command(flatten)]
+        encode: EncodeOptions,
+        #[command(flatten)]
+        metal_rough: MetalRoughOptions,
+        #[command(flatten)]
+        layout: LayoutOptions,
+        /// The maximum number of images or encoder processes to work on at
+        /// once. Defaults to the number of CPUs.
+        #[arg(short, long)]
+        jobs: Option<usize>,
         /// The output directory. Will be created if it does not exist.
         #[arg(short, long)]
         output: PathBuf,
+        #[command(flatten)]
+        dry_run: DryRunOptions,
+    },
+    /// Print the properties of KTX2 files.
+    ///
+    /// Shows the format, transfer function, dimensions, array layers, mip
+    /// levels and supercompression of each file, as read from its header and
+    /// data format descriptor.
+    Inspect {
+        /// The KTX2 files.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
     },
 }
 
 fn main() -> anyhow::Result<()> {
-    match Args::parse() {
+    let args = Args::parse();
+    install_interrupt_handler()?;
+
+    let (jobs, dry_run) = match &args {
+        // Inspecting only reads a few headers.
+        Args::Inspect { files } => return inspect(files),
+        Args::GuessInput { dry_run, .. } => (None, dry_run.clone()),
+        Args::ConvertImages { jobs, dry_run, .. }
+        | Args::MakeArrayMaterial { jobs, dry_run, .. }
+        | Args::FeelingLucky { jobs, dry_run, .. } => (*jobs, dry_run.clone()),
+    };
+    // A dry run doesn't do any heavy work, and a single thread keeps the steps
+    // of the plan in order.
+    let num_threads = if dry_run.dry_run {
+        1
+    } else {
+        jobs.unwrap_or(0)
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()?;
+
+    let planner = Planner::new(dry_run.dry_run);
+    match args {
         Args::GuessInput {
             input: input_directory,
             output: output_file,
+            ..
         } => guess_input(
             &input_directory,
             &output_file.unwrap_or_else(|| input_directory.join("guesses").with_extension("ron")),
-        ),
+            &planner,
+        )?,
         Args::ConvertImages {
             assignments: assignment_file,
             material_format,
             texture_format,
+            mips,
+            encode,
+            metal_rough,
             output: output_directory,
+            ..
         } => convert_images(
             &assignment_file,
             material_format,
             texture_format,
+            &mips,
+            &encode,
+            &metal_rough,
             &output_directory,
-        ),
+            &planner,
+        )?,
         Args::MakeArrayMaterial {
             input: input_directories,
             texture_format,
+            mips,
+            encode,
+            layout,
             output: output_directory,
-        } => make_array_material(&input_directories, texture_format, &output_directory),
+            ..
+        } => make_array_material(
+            &input_directories,
+            texture_format,
+            &mips,
+            &encode,
+            &layout,
+            &output_directory,
+            &planner,
+        )?,
         Args::FeelingLucky {
             input: input_directories,
             material_format,
             texture_format,
+            mips,
+            encode,
+            metal_rough,
+            layout,
             output: output_directory,
+            ..
         } => feeling_lucky(
             &input_directories,
             material_format,
             texture_format,
+            &mips,
+            &encode,
+            &metal_rough,
+            &layout,
             &output_directory,
-        ),
+            &planner,
+        )?,
+        Args::Inspect { .. } => unreachable!("inspect is run before planning"),
     }
+
+    if dry_run.dry_run {
+        planner.into_plan().print(dry_run.plan_format)?;
+    }
+
+    Ok(())
 }
@@ -1,62 +1,1170 @@
-use super::MaterialAttribute;
-use crate::toktx::toktx2_array;
-use crate::{Ktx2TextureCodec, TextureFormat};
+use crate::atlas::AtlasGrid;
+use crate::cache::{BuildCache, CacheKeyBuilder};
+use crate::convert_images::write_native_texture;
+use crate::dds::DdsTexture;
+use crate::encoder::{self, EncodeJob};
+use crate::interrupt;
+use crate::ktx2::{Ktx2Format, Ktx2Info, Ktx2Texture, VkFormat};
+use crate::mipmap::{
+    bake_normal_variance, generate_mips, mip_level_count, mip_path, preserve_alpha_coverage,
+};
+use crate::output;
+use crate::plan::{PlanStep, Planner};
+use crate::raster;
+use crate::registry::LayerRegistry;
+use crate::staging::StagingDir;
+use crate::{
+    ArrayLayout, ArrayMaterial, ArrayMetadata, EncodeOptions, Ktx2TextureCodec, LayoutOptions,
+    MaterialAttribute, MaterialMetadata, MipGeneration, MipOptions, TextureFormat,
+};
 use anyhow::Context;
-use image::GenericImage;
-use std::fs::File;
+use clap::ValueEnum;
+use image::{
+    DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageFormat, Pixel, Rgb, RgbImage,
+};
+use rayon::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-/// When using `TextureFormat::Ktx2*`, it is assumed that images in
-/// `input_directories" are already in an input format supported by "toktx".
+/// Each layer is the file in its input directory named after its attribute,
+/// in any format the "image" crate reads or KTX2. KTX2 layers that are already
+/// in the output's format are copied without decoding. Otherwise a lossless
+/// image is preferred, then an uncompressed KTX2 file, and it's an error before
+/// anything is written if a layer is only block-compressed. Layers that aren't
+/// PNG are staged as PNG for encoders.
 pub fn make_array_material(
     input_directories: &[PathBuf],
     texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    layout: &LayoutOptions,
     output_directory: &Path,
+    planner: &Planner,
 ) -> anyhow::Result<()> {
-    std::fs::create_dir_all(output_directory)?;
+    mips.validate()?;
+    layout.validate()?;
+    let metadata = load_input_metadata(input_directories)?;
+    let encode = &encode.resolve(texture_format, mips, planner)?;
 
-    // All of the metadata has to match, so we'll just take that of the first one.
-    let first_dir = &input_directories[0];
-    let meta_path = first_dir.join("metadata").with_extension("ron");
-    let metadata: Vec<(MaterialAttribute, PathBuf, (u32, u32))> =
-        ron::de::from_reader(File::open(&meta_path).with_context(|| format!("{meta_path:?}"))?)?;
+    make_array_from_metadata(
+        input_directories,
+        &metadata,
+        texture_format,
+        mips,
+        encode,
+        layout,
+        output_directory,
+        planner,
+    )
+}
+
+/// Load the metadata of every input directory and check that they all match,
+/// returning that of the first.
+fn load_input_metadata(input_directories: &[PathBuf]) -> anyhow::Result<MaterialMetadata> {
+    let (metadata, errors): (Vec<_>, Vec<_>) = input_directories
+        .iter()
+        .map(|dir| MaterialMetadata::load(dir).map_err(|e| format!("    {e:#}")))
+        .partition(Result::is_ok);
+    if !errors.is_empty() {
+        anyhow::bail!(
+            "Couldn't load the metadata of {} of {} input directories:\n{}",
+            errors.len(),
+            input_directories.len(),
+            errors
+                .into_iter()
+                .map(Result::unwrap_err)
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+    let metadata: Vec<_> = metadata.into_iter().map(Result::unwrap).collect();
+    check_materials_match(input_directories, &metadata)?;
+    Ok(metadata.into_iter().next().unwrap())
+}
+
+/// Check that every material has the same attributes as the first, with the
+/// same dimensions, since the array is laid out from the first. Every
+/// difference is reported in one table.
+pub(crate) fn check_materials_match(
+    input_directories: &[PathBuf],
+    metadata: &[MaterialMetadata],
+) -> anyhow::Result<()> {
+    let Some(first) = metadata.first() else {
+        anyhow::bail!("No input directories were given");
+    };
+    let dimensions = |metadata: &MaterialMetadata, attr| {
+        metadata
+            .images
+            .iter()
+            .find_map(|&(a, _, dimensions)| (a == attr).then_some(dimensions))
+    };
+    let attributes: Vec<MaterialAttribute> = MaterialAttribute::ALL
+        .into_iter()
+        .filter(|&attr| metadata.iter().any(|m| dimensions(m, attr).is_some()))
+        .collect();
+    let mismatched: Vec<usize> = (1..metadata.len())
+        .filter(|&i| {
+            attributes
+                .iter()
+                .any(|&attr| dimensions(&metadata[i], attr) != dimensions(first, attr))
+        })
+        .collect();
+    if mismatched.is_empty() {
+        return Ok(());
+    }
+
+    let mut table = vec![std::iter::once("material".to_owned())
+        .chain(
+            attributes
+                .iter()
+                .map(|attr| attr.canonical_name().to_owned()),
+        )
+        .collect::<Vec<_>>()];
+    for i in std::iter::once(0).chain(mismatched.iter().copied()) {
+        let mut row = vec![format!("{}", input_directories[i].display())];
+        for &attr in &attributes {
+            let cell = match dimensions(&metadata[i], attr) {
+                Some((width, height)) => format!("{width}x{height}"),
+                None => "missing".to_owned(),
+            };
+            let differs = i > 0 && dimensions(&metadata[i], attr) != dimensions(first, attr);
+            row.push(if differs { format!("{cell} *") } else { cell });
+        }
+        table.push(row);
+    }
+    let widths: Vec<usize> = (0..table[0].len())
+        .map(|column| table.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    let table: Vec<String> = table
+        .iter()
+        .map(|row| {
+            let cells: Vec<_> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &width)| format!("{cell:width$}"))
+                .collect();
+            format!("    {}", cells.join("  ").trim_end())
+        })
+        .collect();
+    anyhow::bail!(
+        "{} of {} materials don't have the same attributes and dimensions as the first, {:?}:\n\
+         {}\n\
+         Differences from the first are marked with *",
+        mismatched.len(),
+        metadata.len(),
+        input_directories[0],
+        table.join("\n")
+    )
+}
+
+/// Like `make_array_material`, but with the metadata of the first input
+/// directory already in memory.
+///
+/// Each material goes on the layer it has in the "layers.ron" registry in
+/// `output_directory`, and new materials are added to it. The layers of
+/// removed materials are filled with the first material until the registry is
+/// compacted.
+///
+/// If the materials don't all fit in one texture within the limits of
+/// `layout`, they are split into parts, each written to a "part<N>"
+/// subdirectory of `output_directory`. Either way, where each material ended
+/// up is written to "array.ron" in `output_directory`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn make_array_from_metadata(
+    input_directories: &[PathBuf],
+    metadata: &MaterialMetadata,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    layout: &LayoutOptions,
+    output_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<()> {
+    let mut registry = LayerRegistry::load(output_directory)?;
+    let layer_inputs = registry.assign(input_directories, layout.compact_layers)?;
+    let layer_directories: Vec<PathBuf> = layer_inputs
+        .iter()
+        .map(|&input| input_directories[input.unwrap_or(0)].clone())
+        .collect();
+
+    let layers_per_part =
+        layers_per_part(metadata, layer_directories.len(), texture_format, layout)?;
+    let parts: Vec<_> = layer_directories.chunks(layers_per_part).collect();
+    if parts.len() > 1 {
+        eprintln!(
+            "Splitting {} layers into {} parts of up to {layers_per_part} to stay within \
+             --max-layers {} and --max-dimension {}",
+            layer_directories.len(),
+            parts.len(),
+            layout
+                .max_layers
+                .map_or_else(|| "(none)".to_owned(), |max| max.to_string()),
+            layout.max_dimension
+        );
+    }
+
+    let mut materials = Vec::with_capacity(layer_directories.len());
+    for (part, part_directories) in parts.iter().enumerate() {
+        let part_directory = match parts.len() {
+            1 => output_directory.to_owned(),
+            _ => output_directory.join(format!("part{part}")),
+        };
+        materials.extend(make_array_part(
+            part_directories,
+            metadata,
+            texture_format,
+            mips,
+            encode,
+            layout,
+            &part_directory,
+            planner,
+        )?);
+    }
+
+    let array_metadata = ArrayMetadata {
+        layout: layout.layout,
+        materials: materials
+            .into_iter()
+            .zip(&layer_inputs)
+            .filter(|(_, input)| input.is_some())
+            .map(|(material, _)| material)
+            .collect(),
+    };
+    planner.record(PlanStep::WriteFile {
+        path: ArrayMetadata::path(output_directory),
+    });
+    planner.record(PlanStep::WriteFile {
+        path: LayerRegistry::path(output_directory),
+    });
+    if !planner.is_dry_run() {
+        array_metadata.save(output_directory)?;
+        registry.save(output_directory)?;
+    }
+
+    Ok(())
+}
+
+/// The most materials that fit in one texture of every attribute within the
+/// limits of `layout`.
+fn layers_per_part(
+    metadata: &MaterialMetadata,
+    num_layers: usize,
+    texture_format: TextureFormat,
+    layout: &LayoutOptions,
+) -> anyhow::Result<usize> {
+    let output_dimensions = |num_layers: usize, (width, height): (u32, u32)| match layout.layout {
+        ArrayLayout::Grid => {
+            let (width, height) = AtlasGrid::new(num_layers, (width, height), layout).dimensions();
+            (width as u64, height as u64)
+        }
+        // Outputs that can't hold arrays are stacked vertically.
+        ArrayLayout::Stack if texture_format.is_raster() => {
+            (width as u64, height as u64 * num_layers as u64)
+        }
+        ArrayLayout::Stack => (width as u64, height as u64),
+    };
+    let max_dimension = layout.max_dimension as u64;
+    let fits = |num_layers: usize| {
+        metadata.images.iter().all(|&(_, _, dimensions)| {
+            let (width, height) = output_dimensions(num_layers, dimensions);
+            width <= max_dimension && height <= max_dimension
+        })
+    };
+
+    if !fits(1) {
+        let (attr, _, (width, height)) = metadata
+            .images
+            .iter()
+            .find(|&&(_, _, dimensions)| {
+                let (width, height) = output_dimensions(1, dimensions);
+                width > max_dimension || height > max_dimension
+            })
+            .unwrap();
+        anyhow::bail!(
+            "The {attr:?} images are {width}x{height}, so even a single material doesn't fit \
+             within --max-dimension {max_dimension}"
+        );
+    }
+
+    let max_layers = layout.max_layers.map_or(num_layers, |max| max as usize);
+    let mut layers_per_part = 1;
+    while layers_per_part < num_layers.min(max_layers) && fits(layers_per_part + 1) {
+        layers_per_part += 1;
+    }
+    Ok(layers_per_part)
+}
+
+/// Write the textures of one part of the array into `output_directory`, and
+/// return where each of its materials ended up.
+#[allow(clippy::too_many_arguments)]
+fn make_array_part(
+    input_directories: &[PathBuf],
+    metadata: &MaterialMetadata,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    layout: &LayoutOptions,
+    output_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<Vec<ArrayMaterial>> {
+    if !planner.is_dry_run() {
+        std::fs::create_dir_all(output_directory)?;
+    }
 
     let num_layers = input_directories.len();
 
-    match texture_format {
-        TextureFormat::Png => {
-            // Manually create stacked array images.
-            for (attr, _path, (width, height)) in metadata {
-                let mut concat_img = attr.new_image(width, height * num_layers as u32);
-                for (i, in_dir) in input_directories.iter().enumerate() {
-                    let start_y = i as u32 * height;
-                    let img_path = in_dir.join(attr.canonical_name()).with_extension("png");
-                    let img = image::open(&img_path).with_context(|| format!("{img_path:?}"))?;
-                    concat_img.copy_from(&img, 0, start_y)?;
+    // Only reassemble the arrays whose layers have changed since the last run.
+    let cache = Mutex::new(BuildCache::load(output_directory));
+    let staging = StagingDir::new(output_directory, encode.keep_intermediates, planner)?;
+
+    metadata
+        .images
+        .par_iter()
+        .try_for_each(|&(attr, _, (width, height))| {
+            let grid = (layout.layout == ArrayLayout::Grid)
+                .then(|| AtlasGrid::new(num_layers, (width, height), layout));
+            let base_dimensions = grid.map_or((width, height), |grid| grid.dimensions());
+            let num_levels = mips.level_count(texture_format, base_dimensions);
+            // Encoders generate the rest of the levels themselves.
+            let output_levels = match mips.generation {
+                MipGeneration::Toktx => mip_level_count(base_dimensions),
+                _ => num_levels,
+            };
+            // Atlases are always drawn from decoded layers.
+            let passthrough = match grid {
+                None => passthrough_format(attr, texture_format, mips, encode),
+                Some(_) => None,
+            };
+            let (input_paths, passthrough) =
+                choose_layers(input_directories, attr, passthrough, output_levels, planner)?;
+            let outputs: Vec<PathBuf> = match texture_format.is_raster() {
+                true => (0..num_levels)
+                    .map(|level| {
+                        mip_path(output_directory, attr, level, texture_format.extension())
+                    })
+                    .collect(),
+                false => vec![mip_path(
+                    output_directory,
+                    attr,
+                    0,
+                    texture_format.extension(),
+                )],
+            };
+
+            // In a dry run, the layers might not have been converted yet.
+            let layers_exist = input_paths.iter().all(|path| path.is_file());
+            let key = if layers_exist || !planner.is_dry_run() {
+                let mut key = CacheKeyBuilder::default()
+                    .setting(&attr)
+                    .setting(&texture_format)
+                    .setting(&mips.cache_settings(attr))
+                    .setting(&encode.cache_settings(attr, texture_format))
+                    .setting(&layout.cache_settings());
+                for path in &input_paths {
+                    key = key.file(path)?;
+                    if let Some(normal_path) = specular_aa_normal(attr, path, mips) {
+                        key = key.file(&normal_path)?;
+                    }
                 }
-                concat_img.save(
-                    output_directory
-                        .join(attr.canonical_name())
-                        .with_extension("png"),
+                Some(key.finish())
+            } else {
+                None
+            };
+            let up_to_date = key.as_ref().map_or(false, |key| {
+                cache.lock().unwrap().is_up_to_date(key, &outputs)
+            });
+
+            planner.record(match grid {
+                None => PlanStep::StackLayers {
+                    attribute: attr,
+                    layers: input_paths.clone(),
+                    layer_dimensions: (width, height),
+                    mip_levels: num_levels,
+                    output: outputs[0].clone(),
+                    up_to_date,
+                },
+                Some(grid) => PlanStep::PackAtlas {
+                    attribute: attr,
+                    layers: input_paths.clone(),
+                    layer_dimensions: (width, height),
+                    grid: (grid.columns, grid.rows),
+                    gutter: grid.gutter,
+                    mip_levels: num_levels,
+                    output: outputs[0].clone(),
+                    up_to_date,
+                },
+            });
+            if up_to_date {
+                eprintln!("Skipping {attr:?}, {:?} is up to date", outputs[0]);
+                return Ok(());
+            }
+            interrupt::check()?;
+
+            if let Some(grid) = &grid {
+                write_atlas(
+                    attr,
+                    &input_paths,
+                    grid,
+                    num_levels,
+                    texture_format,
+                    mips,
+                    encode,
+                    &outputs,
+                    staging.path(),
+                    planner,
                 )?;
+            } else if let Some(format) = passthrough {
+                if !planner.is_dry_run() {
+                    copy_ktx2_layers(
+                        &input_paths,
+                        format,
+                        (width, height),
+                        output_levels,
+                        texture_format,
+                        encode,
+                        &outputs[0],
+                    )?;
+                }
+            } else {
+                match texture_format.encoder_codec(attr) {
+                    None => {
+                        if planner.is_dry_run() {
+                            return Ok(());
+                        }
+
+                        let layer_levels = input_paths
+                            .par_iter()
+                            .map(|img_path| {
+                                if num_levels > 1 {
+                                    layer_mips(
+                                        attr,
+                                        img_path,
+                                        (width, height),
+                                        texture_format,
+                                        mips,
+                                    )
+                                } else {
+                                    let img = open_layer(img_path, (width, height))?;
+                                    Ok(vec![texture_format.convert_image(attr, &img)])
+                                }
+                            })
+                            .collect::<anyhow::Result<Vec<_>>>()?;
+
+                        if let Some(format) =
+                            VkFormat::for_texture_format(texture_format, attr, encode)
+                        {
+                            write_native_texture(
+                                texture_format,
+                                format,
+                                &layer_levels,
+                                true,
+                                encode,
+                                &outputs[0],
+                            )?;
+                        } else {
+                            // Manually create stacked array images, one per mip level.
+                            for (level, output_path) in outputs.iter().enumerate() {
+                                let layers: Vec<_> =
+                                    layer_levels.iter().map(|levels| &levels[level]).collect();
+                                let concat_img =
+                                    texture_format.convert_image(attr, &stack_layers(&layers)?);
+                                raster::save(&concat_img, output_path)?;
+                            }
+                        }
+                    }
+                    Some(codec) => {
+                        let layer_paths = if num_levels > 1 {
+                            stage_layer_mips(
+                                attr,
+                                &input_paths,
+                                (width, height),
+                                num_levels,
+                                mips,
+                                staging.path(),
+                                planner,
+                            )?
+                        } else {
+                            stage_base_levels(
+                                attr,
+                                &input_paths,
+                                (width, height),
+                                staging.path(),
+                                planner,
+                            )?
+                        };
+
+                        // Let the encoder stack the images for us, encoding one
+                        // texture per attribute.
+                        let job = EncodeJob {
+                            attribute: attr,
+                            codec,
+                            layer_paths: &layer_paths,
+                            is_array: true,
+                            mips: mips.generation,
+                            encode,
+                            output_path: &outputs[0],
+                            staging_directory: staging.path(),
+                        };
+                        encoder::encode(&job, planner)?;
+                    }
+                }
             }
-        }
-        TextureFormat::Ktx2Astc => {
-            // PERF: parallelize this
-            // Use the "toktx" tool to stack the images for us.
-            for (attr, ..) in metadata {
-                let input_paths: Vec<PathBuf> = input_directories
+
+            if let Some(key) = key {
+                cache.lock().unwrap().insert(&key, &outputs)?;
+            }
+            anyhow::Ok(())
+        })?;
+
+    if !planner.is_dry_run() {
+        cache.into_inner().unwrap().save(output_directory)?;
+    }
+
+    let materials = input_directories
+        .iter()
+        .enumerate()
+        .map(|(layer, input_directory)| ArrayMaterial {
+            source: input_directory.clone(),
+            files: metadata
+                .images
+                .iter()
+                .map(|&(attr, _, _)| {
+                    let path = mip_path(output_directory, attr, 0, texture_format.extension());
+                    (attr, path)
+                })
+                .collect(),
+            layer: layer as u32,
+            uv_rects: match layout.layout {
+                ArrayLayout::Stack => Vec::new(),
+                ArrayLayout::Grid => metadata
+                    .images
                     .iter()
-                    // TODO: don't assume PNG here
-                    .map(|in_dir| in_dir.join(attr.canonical_name()).with_extension("png"))
-                    .collect();
-                let output_path = output_directory
-                    .join(attr.canonical_name())
-                    .with_extension("ktx2");
-                toktx2_array(&input_paths, attr, Ktx2TextureCodec::Astc, &output_path)?;
+                    .map(|&(attr, _, dimensions)| {
+                        let grid = AtlasGrid::new(num_layers, dimensions, layout);
+                        (attr, grid.uv_rect(layer))
+                    })
+                    .collect(),
+            },
+        })
+        .collect();
+    Ok(materials)
+}
+
+/// Pack the layers of `attr` into `grid` and write the atlas, with its mips,
+/// as a 2D texture.
+///
+/// The mips are generated from the whole atlas, so the gutters keep the edges
+/// of each cell from picking up its neighbours until the smallest levels.
+#[allow(clippy::too_many_arguments)]
+fn write_atlas(
+    attr: MaterialAttribute,
+    input_paths: &[PathBuf],
+    grid: &AtlasGrid,
+    num_levels: usize,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+    outputs: &[PathBuf],
+    staging_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<()> {
+    let codec = texture_format.encoder_codec(attr);
+    let staged_paths: Vec<PathBuf> = (0..num_levels)
+        .map(|level| mip_path(staging_directory, attr, level, "png"))
+        .collect();
+
+    if !planner.is_dry_run() {
+        let layers = input_paths
+            .par_iter()
+            .map(|path| open_layer(path, grid.layer_dimensions))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let atlas = grid.pack(&layers);
+
+        // Encoders are given PNG levels.
+        let level_format = match codec {
+            Some(_) => TextureFormat::Png,
+            None => texture_format,
+        };
+        let levels = if num_levels > 1 {
+            let mut levels = generate_mips(attr, &atlas, level_format);
+            if let Some(normal) = atlas_normal(attr, input_paths, grid, mips)? {
+                bake_normal_variance(&normal, &mut levels, mips.specular_aa_strength);
+            }
+            if let (MaterialAttribute::Albedo, Some(cutoff)) = (attr, mips.alpha_cutoff) {
+                preserve_alpha_coverage(&mut levels, cutoff);
+            }
+            levels
+        } else {
+            vec![level_format.convert_image(attr, &atlas)]
+        };
+
+        match (
+            codec,
+            VkFormat::for_texture_format(texture_format, attr, encode),
+        ) {
+            (Some(_), _) => {
+                for (level, path) in levels.iter().zip(&staged_paths) {
+                    raster::save(level, path)?;
+                }
+            }
+            (None, Some(format)) => write_native_texture(
+                texture_format,
+                format,
+                &[levels],
+                false,
+                encode,
+                &outputs[0],
+            )?,
+            (None, None) => {
+                for (level, path) in levels.iter().zip(outputs) {
+                    raster::save(level, path)?;
+                }
             }
         }
     }
 
+    if let Some(codec) = codec {
+        let job = EncodeJob {
+            attribute: attr,
+            codec,
+            layer_paths: &[staged_paths],
+            is_array: false,
+            mips: mips.generation,
+            encode,
+            output_path: &outputs[0],
+            staging_directory,
+        };
+        encoder::encode(&job, planner)?;
+    }
     Ok(())
 }
+
+/// The normal layers that go with the layers of a metal_rough atlas, packed
+/// into the same grid, if their variance should be baked into it. Layers
+/// without a normal map get a flat one.
+fn atlas_normal(
+    attr: MaterialAttribute,
+    input_paths: &[PathBuf],
+    grid: &AtlasGrid,
+    mips: &MipOptions,
+) -> anyhow::Result<Option<DynamicImage>> {
+    let normal_paths: Vec<_> = input_paths
+        .iter()
+        .map(|path| specular_aa_normal(attr, path, mips))
+        .collect();
+    if normal_paths.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let (width, height) = grid.layer_dimensions;
+    let normals = normal_paths
+        .par_iter()
+        .map(|normal_path| {
+            let Some(normal_path) = normal_path else {
+                let flat = RgbImage::from_pixel(width, height, Rgb([128, 128, 255]));
+                return Ok(DynamicImage::ImageRgb8(flat));
+            };
+            let normal = raster::open(normal_path)?;
+            // The grid is laid out for the metal_rough layers.
+            Ok(normal.resize_exact(width, height, image::imageops::FilterType::Triangle))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(Some(grid.pack(&normals)))
+}
+
+/// Stack `layers` of the same size from top to bottom, in RGBA with the
+/// widest channels of any layer so that no bit depth is lost.
+fn stack_layers(layers: &[&DynamicImage]) -> anyhow::Result<DynamicImage> {
+    fn stack_as<P: Pixel>(
+        layers: &[&DynamicImage],
+        convert: impl Fn(&DynamicImage) -> ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> anyhow::Result<ImageBuffer<P, Vec<P::Subpixel>>> {
+        let (width, height) = layers.first().map_or((0, 0), |layer| layer.dimensions());
+        let mut stacked = ImageBuffer::new(width, height * layers.len() as u32);
+        for (i, layer) in layers.iter().enumerate() {
+            stacked.copy_from(&convert(layer), 0, i as u32 * height)?;
+        }
+        Ok(stacked)
+    }
+
+    Ok(match raster::channel_bytes(layers.iter().copied()) {
+        1 => DynamicImage::ImageRgba8(stack_as(layers, DynamicImage::to_rgba8)?),
+        2 => DynamicImage::ImageRgba16(stack_as(layers, DynamicImage::to_rgba16)?),
+        _ => DynamicImage::ImageRgba32F(stack_as(layers, DynamicImage::to_rgba32f)?),
+    })
+}
+
+/// Generate the mips of each layer and write them to PNG files in
+/// `staging_directory` for the encoder to read. Returns the paths of each level
+/// of each layer, where level 0 is the layer's input path if it is a PNG.
+fn stage_layer_mips(
+    attr: MaterialAttribute,
+    input_paths: &[PathBuf],
+    dimensions: (u32, u32),
+    num_levels: usize,
+    mips: &MipOptions,
+    staging_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<Vec<Vec<PathBuf>>> {
+    input_paths
+        .par_iter()
+        .enumerate()
+        .map(|(layer, input_path)| {
+            let layer_dir = staging_directory.join(format!("layer{layer}"));
+            let mut paths = vec![encoder_base_path(attr, input_path, &layer_dir)];
+            paths.extend((1..num_levels).map(|level| mip_path(&layer_dir, attr, level, "png")));
+
+            if !planner.is_dry_run() {
+                std::fs::create_dir_all(&layer_dir)?;
+                let levels = layer_mips(attr, input_path, dimensions, TextureFormat::Png, mips)?;
+                let skip = (paths[0] == *input_path) as usize;
+                for (level, path) in levels.iter().zip(&paths).skip(skip) {
+                    raster::save(level, path)?;
+                }
+            }
+
+            Ok(paths)
+        })
+        .collect()
+}
+
+/// Like `stage_layer_mips` without mips, so only the layers that aren't PNG
+/// are staged.
+fn stage_base_levels(
+    attr: MaterialAttribute,
+    input_paths: &[PathBuf],
+    dimensions: (u32, u32),
+    staging_directory: &Path,
+    planner: &Planner,
+) -> anyhow::Result<Vec<Vec<PathBuf>>> {
+    input_paths
+        .par_iter()
+        .enumerate()
+        .map(|(layer, input_path)| {
+            let layer_dir = staging_directory.join(format!("layer{layer}"));
+            let path = encoder_base_path(attr, input_path, &layer_dir);
+            if path != *input_path && !planner.is_dry_run() {
+                std::fs::create_dir_all(&layer_dir)?;
+                let img = open_layer(input_path, dimensions)?;
+                raster::save(&TextureFormat::Png.convert_image(attr, &img), &path)?;
+            }
+            Ok(vec![path])
+        })
+        .collect()
+}
+
+/// Where an encoder reads the base level of a layer from. Encoders are only
+/// given PNG files, so other layers are staged in `layer_directory`.
+fn encoder_base_path(
+    attr: MaterialAttribute,
+    input_path: &Path,
+    layer_directory: &Path,
+) -> PathBuf {
+    match raster::has_extension(input_path, "png") {
+        true => input_path.to_owned(),
+        false => mip_path(layer_directory, attr, 0, "png"),
+    }
+}
+
+/// The format of the array texture of `attr`, if KTX2 layers that are already
+/// in that format can have their texel data copied as is.
+///
+/// The mips of layers can't be adjusted for `--alpha-cutoff` or
+/// `--specular-aa` without decoding them, so those are always converted.
+fn passthrough_format(
+    attr: MaterialAttribute,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    encode: &EncodeOptions,
+) -> Option<VkFormat> {
+    let adjusts_mips = match attr {
+        MaterialAttribute::Albedo => mips.alpha_cutoff.is_some(),
+        MaterialAttribute::MetallicRoughness => mips.specular_aa,
+        _ => false,
+    };
+    if adjusts_mips {
+        return None;
+    }
+    match texture_format.encoder_codec(attr) {
+        None => VkFormat::for_texture_format(texture_format, attr, encode),
+        Some(Ktx2TextureCodec::Astc) => Some(VkFormat::Astc {
+            block_size: encode.astc_block_size(attr),
+            srgb: attr == MaterialAttribute::Albedo,
+        }),
+        // Basis Universal textures aren't `VkFormat`s.
+        Some(Ktx2TextureCodec::Uastc | Ktx2TextureCodec::Etc1s) => None,
+    }
+}
+
+/// Whether `path` is a KTX2 layer in `format` with exactly `num_levels` mip
+/// levels. Layers with another number of levels were made with other mip
+/// settings, so they're converted instead.
+fn can_copy_layer(path: &Path, format: VkFormat, num_levels: usize) -> bool {
+    raster::has_extension(path, "ktx2")
+        && Ktx2Info::read(path).map_or(false, |info| {
+            info.format == Ktx2Format::Vk(format) && info.num_levels() == num_levels
+        })
+}
+
+/// Write the texel data of KTX2 layers that are already in `format`, with
+/// `num_levels` mip levels each, to an array texture.
+///
+/// Nothing is decoded, so the layers keep whatever mips they were converted
+/// with.
+fn copy_ktx2_layers(
+    input_paths: &[PathBuf],
+    format: VkFormat,
+    dimensions: (u32, u32),
+    num_levels: usize,
+    texture_format: TextureFormat,
+    encode: &EncodeOptions,
+    output_path: &Path,
+) -> anyhow::Result<()> {
+    let layer_levels = input_paths
+        .par_iter()
+        .map(|path| {
+            let texture = Ktx2Texture::read(path)?;
+            if (texture.width, texture.height) != dimensions {
+                anyhow::bail!(
+                    "{path:?} is {}x{}, but the first layer is {}x{}",
+                    texture.width,
+                    texture.height,
+                    dimensions.0,
+                    dimensions.1
+                );
+            }
+            if texture.layers.map_or(false, |layers| layers != 1) {
+                anyhow::bail!("{path:?} is already an array texture");
+            }
+            if texture.levels.len() != num_levels {
+                anyhow::bail!(
+                    "{path:?} has {} mip levels, but the array needs {num_levels}; convert it \
+                     again with the same mip options",
+                    texture.levels.len()
+                );
+            }
+            Ok(texture.levels)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (width, height) = dimensions;
+    if texture_format.is_dds() {
+        let texture = DdsTexture {
+            format,
+            width,
+            height,
+            layers: layer_levels,
+        };
+        output::write_with(output_path, |partial_path| texture.write(partial_path))
+    } else {
+        let levels = (0..num_levels)
+            .map(|level| {
+                layer_levels
+                    .iter()
+                    .flat_map(|levels| levels[level].iter().copied())
+                    .collect()
+            })
+            .collect();
+        let texture = Ktx2Texture {
+            format,
+            width,
+            height,
+            layers: Some(input_paths.len() as u32),
+            levels,
+            zstd_level: encode.zstd,
+        };
+        output::write_with(output_path, |partial_path| texture.write(partial_path))
+    }
+}
+
+/// Open a layer and generate its mip chain at the bit depth of
+/// `texture_format`.
+///
+/// With specular anti-aliasing, the variance of the normal layer in the same
+/// directory is baked into the roughness of a metal_rough layer. The alpha
+/// coverage of albedo layers is preserved if `mips` has an alpha cutoff.
+fn layer_mips(
+    attr: MaterialAttribute,
+    path: &Path,
+    dimensions: (u32, u32),
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+) -> anyhow::Result<Vec<DynamicImage>> {
+    let img = open_layer(path, dimensions)?;
+    let mut levels = generate_mips(attr, &img, texture_format);
+    if let Some(normal_path) = specular_aa_normal(attr, path, mips) {
+        let normal = raster::open(&normal_path)?;
+        bake_normal_variance(&normal, &mut levels, mips.specular_aa_strength);
+    }
+    if let (MaterialAttribute::Albedo, Some(cutoff)) = (attr, mips.alpha_cutoff) {
+        preserve_alpha_coverage(&mut levels, cutoff);
+    }
+    Ok(levels)
+}
+
+/// The normal layer that goes with the layer at `path`, if its variance should
+/// be baked into the layer.
+fn specular_aa_normal(attr: MaterialAttribute, path: &Path, mips: &MipOptions) -> Option<PathBuf> {
+    if attr != MaterialAttribute::MetallicRoughness || !mips.specular_aa {
+        return None;
+    }
+    let directory = path.parent()?;
+    let normal_paths = layer_files(directory, MaterialAttribute::Normal).ok()?;
+    decodable_layer(directory, MaterialAttribute::Normal, &normal_paths).ok()
+}
+
+/// Choose the layer of `attr` in each input directory, returning the paths
+/// along with `passthrough` if the layers can be copied in that format.
+///
+/// A directory can hold a layer in several formats, such as the PNG an encoder
+/// was given next to the KTX2 file it wrote. KTX2 layers that can be copied
+/// are chosen if every directory has one. Otherwise every layer is decoded, so
+/// the best layer that can be is chosen from each directory. In a dry run, the
+/// directories might not have been converted yet, so a PNG layer is assumed.
+fn choose_layers(
+    input_directories: &[PathBuf],
+    attr: MaterialAttribute,
+    passthrough: Option<VkFormat>,
+    num_levels: usize,
+    planner: &Planner,
+) -> anyhow::Result<(Vec<PathBuf>, Option<VkFormat>)> {
+    let candidates = input_directories
+        .iter()
+        .map(|directory| match layer_files(directory, attr) {
+            Err(_) if planner.is_dry_run() => Ok(Vec::new()),
+            result => result,
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if let Some(format) = passthrough {
+        let copied: Option<Vec<PathBuf>> = candidates
+            .iter()
+            .map(|paths| {
+                paths
+                    .iter()
+                    .find(|path| can_copy_layer(path, format, num_levels))
+                    .cloned()
+            })
+            .collect();
+        if let Some(copied) = copied {
+            return Ok((copied, Some(format)));
+        }
+    }
+
+    let layers = input_directories
+        .iter()
+        .zip(&candidates)
+        .map(
+            |(directory, paths)| match decodable_layer(directory, attr, paths) {
+                Err(_) if planner.is_dry_run() && paths.is_empty() => {
+                    Ok(mip_path(directory, attr, 0, "png"))
+                }
+                result => result,
+            },
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((layers, None))
+}
+
+/// The layer of `attr` to decode out of `paths` in `directory`: a raster image
+/// in one of the lossless formats we write, else an uncompressed KTX2 file,
+/// else any other image. It's an error if there's more than one of the best
+/// kind, or only block-compressed KTX2 files.
+fn decodable_layer(
+    directory: &Path,
+    attr: MaterialAttribute,
+    paths: &[PathBuf],
+) -> anyhow::Result<PathBuf> {
+    let preference = |path: &Path| {
+        if raster::has_extension(path, "ktx2") {
+            let info = Ktx2Info::read(path).ok()?;
+            matches!(info.format, Ktx2Format::Vk(format) if format.can_decode()).then_some(1)
+        } else if TextureFormat::value_variants()
+            .iter()
+            .any(|format| format.is_raster() && raster::has_extension(path, format.extension()))
+        {
+            Some(0)
+        } else {
+            Some(2)
+        }
+    };
+    let ranked: Vec<(u8, &PathBuf)> = paths
+        .iter()
+        .filter_map(|path| Some((preference(path)?, path)))
+        .collect();
+    let Some(best) = ranked.iter().map(|&(rank, _)| rank).min() else {
+        match paths.first() {
+            None => anyhow::bail!("{directory:?} has no {} image", attr.canonical_name()),
+            Some(path) => anyhow::bail!(
+                "{path:?} is {}, which can't be decoded, and there's no lossless {} image next \
+                 to it to convert instead; convert the material again with a lossless \
+                 --texture-format such as png, or the same one as the array",
+                Ktx2Info::read(path)
+                    .map_or("unreadable".to_owned(), |info| info.format.to_string()),
+                attr.canonical_name()
+            ),
+        }
+    };
+    let best: Vec<&PathBuf> = ranked
+        .into_iter()
+        .filter_map(|(rank, path)| (rank == best).then_some(path))
+        .collect();
+    match best.as_slice() {
+        [path] => Ok(path.to_path_buf()),
+        _ => anyhow::bail!(
+            "{directory:?} has more than one {} image, remove all but one of {best:?}",
+            attr.canonical_name()
+        ),
+    }
+}
+
+/// The images in `directory` named after `attr`, with any extension that is
+/// an image format.
+fn layer_files(directory: &Path, attr: MaterialAttribute) -> anyhow::Result<Vec<PathBuf>> {
+    let mut layers = Vec::new();
+    for entry in std::fs::read_dir(directory).with_context(|| format!("{directory:?}"))? {
+        let path = entry?.path();
+        let is_image = path.extension().map_or(false, |ext| {
+            raster::has_extension(&path, "ktx2") || ImageFormat::from_extension(ext).is_some()
+        });
+        if is_image && path.file_stem() == Some(attr.canonical_name().as_ref()) && path.is_file() {
+            layers.push(path);
+        }
+    }
+    layers.sort();
+    Ok(layers)
+}
+
+fn open_layer(path: &Path, dimensions: (u32, u32)) -> anyhow::Result<DynamicImage> {
+    let img = raster::open(path)?;
+    if img.dimensions() != dimensions {
+        anyhow::bail!(
+            "{path:?} is {}x{}, but the first layer is {}x{}",
+            img.width(),
+            img.height(),
+            dimensions.0,
+            dimensions.1
+        );
+    }
+    Ok(img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use image::{Rgba, RgbaImage};
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        mips: MipOptions,
+        #[command(flatten)]
+        encode: EncodeOptions,
+        #[command(flatten)]
+        layout: LayoutOptions,
+    }
+
+    /// A fresh directory for one test.
+    fn test_directory(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "material-converter-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Write a converted material with a 4x4 albedo PNG of `color`, and
+    /// optionally `ktx2` next to it.
+    fn write_material(directory: &Path, color: [u8; 4], ktx2: Option<&Ktx2Texture>) {
+        std::fs::create_dir_all(directory).unwrap();
+        let albedo = RgbaImage::from_pixel(4, 4, Rgba(color));
+        albedo.save(directory.join("albedo.png")).unwrap();
+        if let Some(texture) = ktx2 {
+            texture.write(&directory.join("albedo.ktx2")).unwrap();
+        }
+        MaterialMetadata {
+            images: vec![(MaterialAttribute::Albedo, "albedo.png".into(), (4, 4))],
+            adjustments: Vec::new(),
+        }
+        .save(directory)
+        .unwrap();
+    }
+
+    fn make_ktx2_array(
+        input_directories: &[PathBuf],
+        output_directory: &Path,
+    ) -> anyhow::Result<()> {
+        let cli = Cli::parse_from(["test", "--mips", "none"]);
+        make_array_material(
+            input_directories,
+            TextureFormat::Ktx2,
+            &cli.mips,
+            &cli.encode,
+            &cli.layout,
+            output_directory,
+            &Planner::new(false),
+        )
+    }
+
+    fn layer_color(texture: &Ktx2Texture, layer: usize) -> Rgba<u8> {
+        *texture.image(0, layer).unwrap().to_rgba8().get_pixel(0, 0)
+    }
+
+    #[test]
+    fn ktx2_layers_next_to_pngs_are_copied() {
+        let root = test_directory("copy");
+        let inputs = [root.join("a"), root.join("b")];
+        for (directory, color) in inputs.iter().zip([[255, 0, 0, 255], [0, 255, 0, 255]]) {
+            // The KTX2 layer is blue, so it can be told apart from the PNG.
+            let blue = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]));
+            let texture = Ktx2Texture::from_layer_levels(
+                VkFormat::R8G8B8A8Srgb,
+                &[vec![DynamicImage::ImageRgba8(blue)]],
+                false,
+            )
+            .unwrap();
+            write_material(directory, color, Some(&texture));
+        }
+
+        make_ktx2_array(&inputs, &root.join("out")).unwrap();
+        let array = Ktx2Texture::read(&root.join("out/albedo.ktx2")).unwrap();
+        assert_eq!(array.layers, Some(2));
+        assert_eq!(layer_color(&array, 0), Rgba([0, 0, 255, 255]));
+        assert_eq!(layer_color(&array, 1), Rgba([0, 0, 255, 255]));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn compressed_ktx2_layers_fall_back_to_pngs() {
+        let root = test_directory("fallback");
+        let bc7 = Ktx2Texture {
+            format: VkFormat::Bc7Srgb,
+            width: 4,
+            height: 4,
+            layers: None,
+            levels: vec![vec![0; 16]],
+            zstd_level: None,
+        };
+        let inputs = [root.join("a"), root.join("b")];
+        write_material(&inputs[0], [255, 0, 0, 255], Some(&bc7));
+        write_material(&inputs[1], [0, 255, 0, 255], None);
+
+        make_ktx2_array(&inputs, &root.join("out")).unwrap();
+        let array = Ktx2Texture::read(&root.join("out/albedo.ktx2")).unwrap();
+        assert_eq!(layer_color(&array, 0), Rgba([255, 0, 0, 255]));
+        assert_eq!(layer_color(&array, 1), Rgba([0, 255, 0, 255]));
+
+        // Without the PNG, nothing is written.
+        std::fs::remove_file(inputs[0].join("albedo.png")).unwrap();
+        let error = make_ktx2_array(&inputs, &root.join("out2")).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("can't be decoded"),
+            "{error:#}"
+        );
+        assert!(!root.join("out2/albedo.ktx2").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
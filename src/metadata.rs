@@ -0,0 +1,113 @@
+use super::{output, ArrayLayout, MaterialAttribute};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The "metadata.ron" file written by `convert_images` into each converted
+/// material directory.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MaterialMetadata {
+    /// The attribute, source path and dimensions of each output image.
+    pub images: Vec<(MaterialAttribute, PathBuf, (u32, u32))>,
+    /// Anything the conversion had to do that wasn't simply a format
+    /// conversion of a source image.
+    #[serde(default)]
+    pub adjustments: Vec<Adjustment>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Adjustment {
+    /// A source image was resampled to match the size of another source image
+    /// that it was combined with.
+    Resampled {
+        attribute: MaterialAttribute,
+        from: (u32, u32),
+        to: (u32, u32),
+    },
+    /// There was no source image for an attribute, so its channel was filled
+    /// with a constant value in [0, 1].
+    Synthesized {
+        attribute: MaterialAttribute,
+        value: f32,
+    },
+}
+
+impl MaterialMetadata {
+    pub fn path(directory: &Path) -> PathBuf {
+        directory.join("metadata").with_extension("ron")
+    }
+
+    /// Load "metadata.ron" from `directory`. Older versions only wrote the
+    /// list of images, which is read as metadata without adjustments.
+    pub fn load(directory: &Path) -> anyhow::Result<Self> {
+        let meta_path = Self::path(directory);
+        let s = std::fs::read_to_string(&meta_path).with_context(|| format!("{meta_path:?}"))?;
+        match ron::from_str(&s) {
+            Ok(metadata) => Ok(metadata),
+            Err(e) => match ron::from_str(&s) {
+                Ok(images) => Ok(Self {
+                    images,
+                    adjustments: Vec::new(),
+                }),
+                Err(_) => Err(e).with_context(|| format!("{meta_path:?}")),
+            },
+        }
+    }
+
+    pub fn save(&self, directory: &Path) -> anyhow::Result<()> {
+        let meta_path = Self::path(directory);
+        output::write(
+            &meta_path,
+            ron::ser::to_string_pretty(self, Default::default())
+                .with_context(|| format!("Couldn't serialize {meta_path:?}"))?,
+        )
+    }
+}
+
+/// The "array.ron" file written by `make_array_material`, which records where
+/// each input material ended up.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ArrayMetadata {
+    pub layout: ArrayLayout,
+    /// The input materials, in layer order. The placeholder layers of removed
+    /// materials are left out.
+    pub materials: Vec<ArrayMaterial>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ArrayMaterial {
+    /// The input directory of the material.
+    pub source: PathBuf,
+    /// The texture of each attribute that the material is in.
+    pub files: Vec<(MaterialAttribute, PathBuf)>,
+    /// The array layer of the material in its textures, or its cell of a grid
+    /// atlas, counting along each row.
+    pub layer: u32,
+    /// The rectangle of the material's cell in the atlas of each attribute,
+    /// without the gutter. Only set for grid atlases.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uv_rects: Vec<(MaterialAttribute, UvRect)>,
+}
+
+/// A rectangle in texture coordinates, with (0, 0) at the top left.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct UvRect {
+    pub min: (f32, f32),
+    pub max: (f32, f32),
+}
+
+impl ArrayMetadata {
+    pub fn path(directory: &Path) -> PathBuf {
+        directory.join("array").with_extension("ron")
+    }
+
+    pub fn save(&self, directory: &Path) -> anyhow::Result<()> {
+        let path = Self::path(directory);
+        std::fs::write(
+            &path,
+            ron::ser::to_string_pretty(self, Default::default())
+                .with_context(|| format!("Couldn't serialize {path:?}"))?,
+        )
+        .with_context(|| format!("{path:?}"))
+    }
+}
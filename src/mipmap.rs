@@ -0,0 +1,449 @@
+use super::{MaterialAttribute, TextureFormat};
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage};
+use std::path::{Path, PathBuf};
+
+type Rgba32FImage = ImageBuffer<Rgba<f32>, Vec<f32>>;
+
+/// The number of levels in a full mip chain, down to 1x1.
+pub fn mip_level_count((width, height): (u32, u32)) -> usize {
+    (32 - width.max(height).max(1).leading_zeros()) as usize
+}
+
+/// The dimensions of mip `level` of an image with base level `dimensions`.
+pub fn mip_dimensions((width, height): (u32, u32), level: usize) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// The path of mip `level` of the `attr` image in `directory`. Level 0 has the
+/// canonical name, so it can be used as if there were no mips.
+pub fn mip_path(
+    directory: &Path,
+    attr: MaterialAttribute,
+    level: usize,
+    extension: &str,
+) -> PathBuf {
+    let name = attr.canonical_name();
+    if level == 0 {
+        directory.join(name).with_extension(extension)
+    } else {
+        directory.join(format!("{name}.mip{level}.{extension}"))
+    }
+}
+
+/// Generate the full mip chain of `img`, starting with `img` itself converted
+/// for `attr` at the bit depth of `texture_format`.
+///
+/// Each level is box filtered from the previous one in a space suited to the
+/// attribute:
+///
+/// - albedo is filtered in linear light, then encoded back to sRGB
+/// - normals are averaged as vectors, then renormalized
+/// - everything else is filtered on the raw values
+///
+/// Only the pixels of `img` are sampled, so generating the chain of each array
+/// layer separately keeps layers from bleeding into each other.
+pub fn generate_mips(
+    attr: MaterialAttribute,
+    img: &DynamicImage,
+    texture_format: TextureFormat,
+) -> Vec<DynamicImage> {
+    let mut level = to_filter_space(attr, img.to_rgba32f());
+    let mut levels = vec![texture_format.convert_image(attr, img)];
+    while level.width() > 1 || level.height() > 1 {
+        level = downsample(&level);
+        let filtered = DynamicImage::ImageRgba32F(from_filter_space(attr, &level));
+        levels.push(texture_format.convert_image(attr, &filtered));
+    }
+    levels
+}
+
+/// Normal variance below this is within the precision of 8-bit normals, so
+/// it's ignored rather than roughening perfectly smooth surfaces.
+const MIN_NORMAL_VARIANCE: f32 = 1e-4;
+
+/// Widen the roughness (green channel) of each `metal_rough_levels` mip by the
+/// variance of the `normal` map over the same footprint (Toksvig).
+///
+/// The variance is estimated from the shortening of the averaged normal
+/// vectors, so it is 0 where a roughness texel covers a single normal and
+/// grows as normal detail is filtered away. `strength` scales how much of it
+/// is added to the squared GGX alpha.
+///
+/// The normal map doesn't need to be the size of the metal_rough image: the
+/// normals are averaged over the footprint of each roughness texel.
+pub fn bake_normal_variance(
+    normal: &DynamicImage,
+    metal_rough_levels: &mut [DynamicImage],
+    strength: f32,
+) {
+    let mut normal_level = to_filter_space(MaterialAttribute::Normal, normal.to_rgba32f());
+    for pixel in normal_level.pixels_mut() {
+        let [x, y, z, _] = pixel.0;
+        let len = (x * x + y * y + z * z).sqrt().max(f32::EPSILON);
+        pixel.0 = [x / len, y / len, z / len, 1.0];
+    }
+    let mut normal_levels = vec![normal_level];
+    while let Some(level) = normal_levels
+        .last()
+        .filter(|l| l.width() > 1 || l.height() > 1)
+    {
+        let smaller = downsample(level);
+        normal_levels.push(smaller);
+    }
+
+    for metal_rough in metal_rough_levels.iter_mut() {
+        let mut rgb = metal_rough.to_rgb8();
+        let (w, h) = rgb.dimensions();
+        // The smallest normal mip that is still at least as large, which
+        // averages to the same footprints with the least work.
+        let normal_level = normal_levels
+            .iter()
+            .rev()
+            .find(|level| level.width() >= w && level.height() >= h)
+            .unwrap_or(&normal_levels[0]);
+        let averaged = average_footprints(normal_level, (w, h));
+
+        for (pixel, normal) in rgb.pixels_mut().zip(averaged.pixels()) {
+            let [x, y, z, _] = normal.0;
+            let len = (x * x + y * y + z * z).sqrt().clamp(f32::EPSILON, 1.0);
+            let variance = (1.0 - len) / len;
+            if variance < MIN_NORMAL_VARIANCE {
+                continue;
+            }
+
+            let roughness = pixel.0[1] as f32 / 255.0;
+            let alpha = roughness * roughness;
+            let alpha2 = (alpha * alpha + strength * variance).min(1.0);
+            pixel.0[1] = (alpha2.sqrt().sqrt() * 255.0).round() as u8;
+        }
+        *metal_rough = DynamicImage::ImageRgb8(rgb);
+    }
+}
+
+/// Average the pixels of `img` that fall within each texel of an image of
+/// `dimensions` covering the same area. Where a texel is smaller than a
+/// pixel of `img`, it takes the pixel it falls in.
+fn average_footprints(img: &Rgba32FImage, (width, height): (u32, u32)) -> Rgba32FImage {
+    let (img_w, img_h) = img.dimensions();
+    if (img_w, img_h) == (width, height) {
+        return img.clone();
+    }
+    let span = |i: u32, size: u32, img_size: u32| {
+        let start = (i as u64 * img_size as u64 / size as u64) as u32;
+        let end = ((i as u64 + 1) * img_size as u64).div_ceil(size as u64) as u32;
+        start..end.max(start + 1).min(img_size)
+    };
+    Rgba32FImage::from_fn(width, height, |x, y| {
+        let mut sum = [0.0; 4];
+        let mut count = 0.0;
+        for sy in span(y, height, img_h) {
+            for sx in span(x, width, img_w) {
+                for (s, c) in sum.iter_mut().zip(img.get_pixel(sx, sy).0) {
+                    *s += c;
+                }
+                count += 1.0;
+            }
+        }
+        Rgba(sum.map(|s| s / count))
+    })
+}
+
+/// Rescale the alpha of each mip in `albedo_levels` so that the fraction of
+/// texels with alpha above `cutoff` matches that of the base level.
+///
+/// Without this, alpha-tested cutouts like leaves thin out and vanish in
+/// smaller mips, because averaging pulls alpha towards the middle.
+pub fn preserve_alpha_coverage(albedo_levels: &mut [DynamicImage], cutoff: f32) {
+    let Some((base, mips)) = albedo_levels.split_first_mut() else {
+        return;
+    };
+    let target = alpha_coverage(&base.to_rgba8(), cutoff, 1.0);
+
+    for level in mips {
+        let mut rgba = level.to_rgba8();
+
+        // Coverage only grows with the scale, so binary search for it.
+        let (mut low, mut high) = (0.0f32, 1.0f32);
+        while alpha_coverage(&rgba, cutoff, high) < target && high < 1024.0 {
+            high *= 2.0;
+        }
+        for _ in 0..24 {
+            let mid = 0.5 * (low + high);
+            if alpha_coverage(&rgba, cutoff, mid) < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        for pixel in rgba.pixels_mut() {
+            let alpha = (pixel.0[3] as f32 * high).min(255.0);
+            pixel.0[3] = alpha.round() as u8;
+        }
+        *level = DynamicImage::ImageRgba8(rgba);
+    }
+}
+
+/// The fraction of texels in `img` with alpha above `cutoff`, after scaling
+/// alpha by `scale`.
+fn alpha_coverage(img: &RgbaImage, cutoff: f32, scale: f32) -> f32 {
+    let covered = img
+        .pixels()
+        .filter(|p| (p.0[3] as f32 / 255.0 * scale).min(1.0) > cutoff)
+        .count();
+    covered as f32 / (img.width() * img.height()) as f32
+}
+
+fn to_filter_space(attr: MaterialAttribute, mut img: Rgba32FImage) -> Rgba32FImage {
+    match attr {
+        MaterialAttribute::Albedo => {
+            for pixel in img.pixels_mut() {
+                for c in &mut pixel.0[..3] {
+                    *c = srgb_to_linear(*c);
+                }
+            }
+        }
+        MaterialAttribute::Normal => {
+            for pixel in img.pixels_mut() {
+                for c in &mut pixel.0[..3] {
+                    *c = *c * 2.0 - 1.0;
+                }
+            }
+        }
+        _ => {}
+    }
+    img
+}
+
+fn from_filter_space(attr: MaterialAttribute, img: &Rgba32FImage) -> Rgba32FImage {
+    let mut img = img.clone();
+    match attr {
+        MaterialAttribute::Albedo => {
+            for pixel in img.pixels_mut() {
+                for c in &mut pixel.0[..3] {
+                    *c = linear_to_srgb(*c);
+                }
+            }
+        }
+        MaterialAttribute::Normal => {
+            for pixel in img.pixels_mut() {
+                let [x, y, z, _] = pixel.0;
+                let len = (x * x + y * y + z * z).sqrt();
+                let n = if len > 0.0 {
+                    [x / len, y / len, z / len]
+                } else {
+                    [0.0, 0.0, 1.0]
+                };
+                for (c, n) in pixel.0[..3].iter_mut().zip(n) {
+                    *c = n * 0.5 + 0.5;
+                }
+            }
+        }
+        _ => {}
+    }
+    img
+}
+
+/// Average each 2x2 block of pixels, clamping at the edges of the image.
+///
+/// Normals are left unnormalized, so each level is the average over its whole
+/// footprint in the base level.
+fn downsample(img: &Rgba32FImage) -> Rgba32FImage {
+    let (w, h) = img.dimensions();
+    let (new_w, new_h) = mip_dimensions((w, h), 1);
+    Rgba32FImage::from_fn(new_w, new_h, |x, y| {
+        let x0 = (2 * x).min(w - 1);
+        let x1 = (2 * x + 1).min(w - 1);
+        let y0 = (2 * y).min(h - 1);
+        let y1 = (2 * y + 1).min(h - 1);
+        let mut sum = [0.0; 4];
+        for (sx, sy) in [(x0, y0), (x1, y0), (x0, y1), (x1, y1)] {
+            for (s, c) in sum.iter_mut().zip(img.get_pixel(sx, sy).0) {
+                *s += c;
+            }
+        }
+        Rgba(sum.map(|s| s * 0.25))
+    })
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, Rgb, RgbImage, RgbaImage};
+
+    fn png_mips(attr: MaterialAttribute, img: &DynamicImage) -> Vec<DynamicImage> {
+        generate_mips(attr, img, TextureFormat::Png)
+    }
+
+    #[test]
+    fn level_counts_and_dimensions() {
+        assert_eq!(mip_level_count((1, 1)), 1);
+        assert_eq!(mip_level_count((256, 256)), 9);
+        assert_eq!(mip_level_count((5, 3)), 3);
+        assert_eq!(mip_level_count((1, 8)), 4);
+
+        assert_eq!(mip_dimensions((5, 3), 1), (2, 1));
+        assert_eq!(mip_dimensions((5, 3), 2), (1, 1));
+        assert_eq!(mip_dimensions((1, 8), 1), (1, 4));
+        assert_eq!(mip_dimensions((1, 8), 3), (1, 1));
+    }
+
+    #[test]
+    fn chain_goes_down_to_1x1() {
+        for dimensions in [(5, 3), (1, 8), (6, 6)] {
+            let img = DynamicImage::new_rgba8(dimensions.0, dimensions.1);
+            let levels = png_mips(MaterialAttribute::AmbientOcclusion, &img);
+            assert_eq!(levels.len(), mip_level_count(dimensions));
+            for (level, img) in levels.iter().enumerate() {
+                assert_eq!(img.dimensions(), mip_dimensions(dimensions, level));
+            }
+        }
+    }
+
+    #[test]
+    fn albedo_is_averaged_in_linear_light() {
+        let checkerboard = RgbaImage::from_fn(2, 2, |x, y| {
+            let c = if (x + y) % 2 == 0 { 255 } else { 0 };
+            image::Rgba([c, c, c, 255])
+        });
+        let levels = png_mips(
+            MaterialAttribute::Albedo,
+            &DynamicImage::ImageRgba8(checkerboard),
+        );
+        let [r, g, b, a] = levels[1].to_rgba8().get_pixel(0, 0).0;
+        // Half of the light is sRGB 188, where averaging the sRGB values would
+        // give 128.
+        assert!((187..=188).contains(&r), "{r}");
+        assert_eq!((g, b, a), (r, r, 255));
+    }
+
+    #[test]
+    fn normals_are_renormalized() {
+        // Two normals tilted 37 degrees either way along X.
+        let normals = RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([204, 128, 230])
+            } else {
+                Rgb([51, 128, 230])
+            }
+        });
+        let levels = png_mips(MaterialAttribute::Normal, &DynamicImage::ImageRgb8(normals));
+        let [x, y, z] = levels[1].to_rgb8().get_pixel(0, 0).0;
+        assert!(x.abs_diff(128) <= 1 && y.abs_diff(128) <= 1, "{x} {y}");
+        // Straight up, rather than the shorter average.
+        assert!(z >= 254, "{z}");
+    }
+
+    fn bake(normals: [[u8; 3]; 2]) -> Vec<u8> {
+        let normal = RgbImage::from_fn(2, 2, |x, _| Rgb(normals[x as usize]));
+        let metal_rough = RgbImage::from_pixel(2, 2, Rgb([0, 128, 0]));
+        let mut levels = png_mips(
+            MaterialAttribute::MetallicRoughness,
+            &DynamicImage::ImageRgb8(metal_rough),
+        );
+        bake_normal_variance(&DynamicImage::ImageRgb8(normal), &mut levels, 1.0);
+        levels
+            .iter()
+            .map(|level| level.to_rgb8().get_pixel(0, 0).0[1])
+            .collect()
+    }
+
+    #[test]
+    fn diverging_normals_raise_roughness() {
+        let roughness = bake([[204, 128, 230], [51, 128, 230]]);
+        assert_eq!(roughness[0], 128);
+        assert!(roughness[1] > 140, "{roughness:?}");
+    }
+
+    #[test]
+    fn flat_normals_keep_roughness() {
+        assert_eq!(bake([[128, 128, 255], [128, 128, 255]]), [128, 128]);
+    }
+
+    #[test]
+    fn larger_normal_map_roughens_the_base_level() {
+        // Each metal_rough texel covers two diverging normals.
+        let normal = RgbImage::from_fn(4, 2, |x, _| {
+            if x % 2 == 0 {
+                Rgb([204, 128, 230])
+            } else {
+                Rgb([51, 128, 230])
+            }
+        });
+        let metal_rough = RgbImage::from_pixel(2, 1, Rgb([0, 128, 0]));
+        let mut levels = png_mips(
+            MaterialAttribute::MetallicRoughness,
+            &DynamicImage::ImageRgb8(metal_rough),
+        );
+        bake_normal_variance(&DynamicImage::ImageRgb8(normal), &mut levels, 1.0);
+        for level in &levels {
+            let roughness = level.to_rgb8().get_pixel(0, 0).0[1];
+            assert!(roughness > 140, "{roughness}");
+        }
+    }
+
+    #[test]
+    fn smaller_normal_map_leaves_the_base_level() {
+        let normal = RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 {
+                Rgb([204, 128, 230])
+            } else {
+                Rgb([51, 128, 230])
+            }
+        });
+        let metal_rough = RgbImage::from_pixel(4, 2, Rgb([0, 128, 0]));
+        let mut levels = png_mips(
+            MaterialAttribute::MetallicRoughness,
+            &DynamicImage::ImageRgb8(metal_rough),
+        );
+        bake_normal_variance(&DynamicImage::ImageRgb8(normal), &mut levels, 1.0);
+        let roughness: Vec<u8> = levels
+            .iter()
+            .map(|level| level.to_rgb8().get_pixel(0, 0).0[1])
+            .collect();
+        // Only the 1x1 level covers both normals.
+        assert_eq!(roughness[..2], [128, 128]);
+        assert!(roughness[2] > 140, "{roughness:?}");
+    }
+
+    #[test]
+    fn alpha_coverage_is_kept_across_levels() {
+        // Noisy alpha, mostly below the cutoff, like the edges of foliage.
+        let albedo = RgbaImage::from_fn(16, 16, |x, y| {
+            let noise =
+                (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)).wrapping_mul(2246822519) >> 24;
+            image::Rgba([0, 128, 0, (noise * 3 / 4) as u8])
+        });
+        let cutoff = 0.5;
+        let mut levels = png_mips(MaterialAttribute::Albedo, &DynamicImage::ImageRgba8(albedo));
+        let target = alpha_coverage(&levels[0].to_rgba8(), cutoff, 1.0);
+        assert!(alpha_coverage(&levels[2].to_rgba8(), cutoff, 1.0) < target - 0.1);
+
+        preserve_alpha_coverage(&mut levels, cutoff);
+        // Down to 4x4, within a couple of texels of the base coverage.
+        for level in &levels[..3] {
+            let coverage = alpha_coverage(&level.to_rgba8(), cutoff, 1.0);
+            let texels = (level.width() * level.height()) as f32;
+            assert!(
+                (coverage - target).abs() <= (2.0 / texels).max(0.05),
+                "{coverage} vs {target}"
+            );
+        }
+    }
+}
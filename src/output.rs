@@ -0,0 +1,45 @@
+use crate::interrupt;
+use anyhow::Context;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Where an output is written before it's complete, such as
+/// "albedo.partial.ktx2" for "albedo.ktx2". The extension is kept, so the
+/// format of an image can still be told from it.
+pub(crate) fn partial_path(path: &Path) -> PathBuf {
+    let mut extension = OsString::from("partial");
+    if let Some(ext) = path.extension() {
+        extension.push(".");
+        extension.push(ext);
+    }
+    path.with_extension(extension)
+}
+
+/// Write an output to `path` by having `write` write it to its partial path,
+/// then renaming it once `write` succeeds.
+///
+/// A failed or interrupted write never leaves a truncated file at `path`, and
+/// the partial file is removed, even if the process exits on a second Ctrl-C.
+pub(crate) fn write_with(
+    path: &Path,
+    write: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let partial_path = partial_path(path);
+    interrupt::remove_on_exit(&partial_path);
+    let result = write(&partial_path).and_then(|()| {
+        std::fs::rename(&partial_path, path)
+            .with_context(|| format!("Couldn't move {partial_path:?} to {path:?}"))
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial_path);
+    }
+    interrupt::keep_on_exit(&partial_path);
+    result
+}
+
+/// Write `contents` to `path` through its partial path.
+pub(crate) fn write(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    write_with(path, |partial_path| {
+        std::fs::write(partial_path, contents).with_context(|| format!("{partial_path:?}"))
+    })
+}
@@ -0,0 +1,223 @@
+use super::{Adjustment, MaterialAttribute};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[derive(Args, Clone, Debug)]
+pub struct DryRunOptions {
+    /// Resolve everything that would be done and print the plan, without
+    /// writing any files or running any external tools.
+    #[arg(long)]
+    pub dry_run: bool,
+    /// How to print the plan of a dry run.
+    #[arg(long, default_value_t = PlanFormat::Human)]
+    pub plan_format: PlanFormat,
+}
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, ValueEnum)]
+pub enum PlanFormat {
+    Human,
+    Json,
+    Ron,
+}
+
+impl fmt::Display for PlanFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Human => write!(f, "human"),
+            Self::Json => write!(f, "json"),
+            Self::Ron => write!(f, "ron"),
+        }
+    }
+}
+
+/// Records every step of a build as it happens.
+///
+/// In a dry run, the steps are resolved and recorded but not executed.
+#[derive(Debug, Default)]
+pub struct Planner {
+    dry_run: bool,
+    steps: Mutex<Vec<PlanStep>>,
+}
+
+impl Planner {
+    pub fn new(dry_run: bool) -> Self {
+        Self {
+            dry_run,
+            steps: Default::default(),
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn record(&self, step: PlanStep) {
+        self.steps.lock().unwrap().push(step);
+    }
+
+    pub fn into_plan(self) -> BuildPlan {
+        BuildPlan {
+            steps: self.steps.into_inner().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuildPlan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl BuildPlan {
+    pub fn print(&self, format: PlanFormat) -> anyhow::Result<()> {
+        match format {
+            PlanFormat::Human => print!("{self}"),
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(self)?),
+            PlanFormat::Ron => {
+                println!("{}", ron::ser::to_string_pretty(self, Default::default())?)
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub enum PlanStep {
+    GuessInput {
+        input_directory: PathBuf,
+        assignments: Vec<(MaterialAttribute, PathBuf)>,
+        output: PathBuf,
+    },
+    ConvertImage {
+        attribute: MaterialAttribute,
+        sources: Vec<PathBuf>,
+        dimensions: (u32, u32),
+        mip_levels: usize,
+        adjustments: Vec<Adjustment>,
+        output: PathBuf,
+        up_to_date: bool,
+    },
+    StackLayers {
+        attribute: MaterialAttribute,
+        layers: Vec<PathBuf>,
+        layer_dimensions: (u32, u32),
+        mip_levels: usize,
+        output: PathBuf,
+        up_to_date: bool,
+    },
+    PackAtlas {
+        attribute: MaterialAttribute,
+        layers: Vec<PathBuf>,
+        layer_dimensions: (u32, u32),
+        /// The number of columns and rows.
+        grid: (u32, u32),
+        gutter: u32,
+        mip_levels: usize,
+        output: PathBuf,
+        up_to_date: bool,
+    },
+    RunCommand {
+        program: String,
+        args: Vec<String>,
+    },
+    WriteFile {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{step}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GuessInput {
+                input_directory,
+                assignments,
+                output,
+            } => {
+                write!(f, "guess {input_directory:?} -> {output:?}")?;
+                for (attr, path) in assignments {
+                    write!(f, "\n    {attr:?}: {path:?}")?;
+                }
+                Ok(())
+            }
+            Self::ConvertImage {
+                attribute,
+                sources,
+                dimensions: (w, h),
+                mip_levels,
+                adjustments,
+                output,
+                up_to_date,
+            } => {
+                write!(
+                    f,
+                    "convert {attribute:?} {sources:?} ({w}x{h}, {mip_levels} levels) -> {output:?}"
+                )?;
+                if *up_to_date {
+                    write!(f, " [up to date]")?;
+                }
+                for adjustment in adjustments {
+                    write!(f, "\n    {adjustment:?}")?;
+                }
+                Ok(())
+            }
+            Self::StackLayers {
+                attribute,
+                layers,
+                layer_dimensions: (w, h),
+                mip_levels,
+                output,
+                up_to_date,
+            } => {
+                write!(
+                    f,
+                    "stack {attribute:?} {} layers ({w}x{h}, {mip_levels} levels) -> {output:?}",
+                    layers.len()
+                )?;
+                if *up_to_date {
+                    write!(f, " [up to date]")?;
+                }
+                for layer in layers {
+                    write!(f, "\n    {layer:?}")?;
+                }
+                Ok(())
+            }
+            Self::PackAtlas {
+                attribute,
+                layers,
+                layer_dimensions: (w, h),
+                grid: (columns, rows),
+                gutter,
+                mip_levels,
+                output,
+                up_to_date,
+            } => {
+                write!(
+                    f,
+                    "pack {attribute:?} {} layers ({w}x{h}, {gutter} gutter) into a {columns}x{rows} \
+                     grid ({mip_levels} levels) -> {output:?}",
+                    layers.len()
+                )?;
+                if *up_to_date {
+                    write!(f, " [up to date]")?;
+                }
+                for layer in layers {
+                    write!(f, "\n    {layer:?}")?;
+                }
+                Ok(())
+            }
+            Self::RunCommand { program, args } => write!(f, "run {program} {}", args.join(" ")),
+            Self::WriteFile { path } => write!(f, "write {path:?}"),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::ktx2::Ktx2Texture;
+use crate::output;
+use anyhow::Context;
+use image::DynamicImage;
+use image_webp::{ColorType, WebPEncoder};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Open an image in any format the "image" crate reads, or the base level of
+/// the first layer of an uncompressed KTX2 file.
+pub fn open(path: &Path) -> anyhow::Result<DynamicImage> {
+    if has_extension(path, "ktx2") {
+        return Ktx2Texture::read(path)?
+            .image(0, 0)
+            .with_context(|| format!("{path:?}"));
+    }
+    image::open(path).with_context(|| format!("{path:?}"))
+}
+
+/// Save `img` in the format given by the extension of `path`.
+///
+/// The "image" crate can't encode WebP without linking libwebp, so WebP
+/// images are encoded losslessly in pure Rust instead. OpenEXR only stores
+/// floats, so other images are converted first.
+pub fn save(img: &DynamicImage, path: &Path) -> anyhow::Result<()> {
+    output::write_with(path, |partial_path| write_image(img, partial_path))
+}
+
+fn write_image(img: &DynamicImage, path: &Path) -> anyhow::Result<()> {
+    if has_extension(path, "exr") {
+        let img = match img {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => img.clone(),
+            _ if img.color().has_alpha() => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+            _ => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        };
+        return img.save(path).with_context(|| format!("{path:?}"));
+    }
+    if !has_extension(path, "webp") {
+        return img.save(path).with_context(|| format!("{path:?}"));
+    }
+
+    let (data, color) = match img {
+        DynamicImage::ImageLuma8(gray) => (gray.as_raw().clone(), ColorType::L8),
+        DynamicImage::ImageRgb8(rgb) => (rgb.as_raw().clone(), ColorType::Rgb8),
+        _ => (img.to_rgba8().into_raw(), ColorType::Rgba8),
+    };
+    let file = File::create(path).with_context(|| format!("{path:?}"))?;
+    WebPEncoder::new(BufWriter::new(file))
+        .encode(&data, img.width(), img.height(), color)
+        .with_context(|| format!("{path:?}"))
+}
+
+/// The number of bytes in each channel of the widest of `images`: 1 or 2 for
+/// integer channels, or 4 for floats.
+pub(crate) fn channel_bytes<'a>(images: impl IntoIterator<Item = &'a DynamicImage>) -> u8 {
+    images
+        .into_iter()
+        .map(|img| img.color().bytes_per_pixel() / img.color().channel_count())
+        .max()
+        .unwrap_or(1)
+}
+
+pub(crate) fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case(extension))
+}
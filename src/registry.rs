@@ -0,0 +1,273 @@
+use crate::output;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// The "layers.ron" file written by `make_array_material`, which keeps each
+/// material on the same layer from one run to the next.
+///
+/// Materials are identified by the name of their input directory. New
+/// materials are added after the existing layers, and the layers of materials
+/// that are no longer given are kept as placeholders until the registry is
+/// compacted.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LayerRegistry {
+    /// What is on each layer, in layer order.
+    pub layers: Vec<RegisteredLayer>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum RegisteredLayer {
+    Material(String),
+    /// The layer of a material that is no longer given. It's filled with
+    /// another material, and given back to the material if it returns.
+    Removed(String),
+}
+
+impl LayerRegistry {
+    pub fn path(directory: &Path) -> PathBuf {
+        directory.join("layers").with_extension("ron")
+    }
+
+    /// Load the registry in `directory`, or start an empty one if there isn't
+    /// one yet.
+    pub fn load(directory: &Path) -> anyhow::Result<Self> {
+        let path = Self::path(directory);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let registry =
+            ron::de::from_reader(File::open(&path).with_context(|| format!("{path:?}"))?)
+                .with_context(|| format!("{path:?}"))?;
+        Ok(registry)
+    }
+
+    pub fn save(&self, directory: &Path) -> anyhow::Result<()> {
+        let path = Self::path(directory);
+        output::write(
+            &path,
+            ron::ser::to_string_pretty(self, Default::default())
+                .with_context(|| format!("Couldn't serialize {path:?}"))?,
+        )
+    }
+
+    /// Give each of `input_directories` a layer, keeping the layers of the
+    /// materials that are already registered. Returns the index of the input
+    /// directory on each layer, or `None` for the placeholder layers of removed
+    /// materials.
+    ///
+    /// With `compact`, the placeholder layers are dropped and the materials
+    /// after them move down.
+    pub(crate) fn assign(
+        &mut self,
+        input_directories: &[PathBuf],
+        compact: bool,
+    ) -> anyhow::Result<Vec<Option<usize>>> {
+        let names = input_directories
+            .iter()
+            .map(|dir| material_name(dir))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut inputs = HashMap::new();
+        for (i, (dir, name)) in input_directories.iter().zip(&names).enumerate() {
+            if let Some(other) = inputs.insert(name.clone(), i) {
+                anyhow::bail!(
+                    "{:?} and {dir:?} are both named {name:?}, so they can't be told apart in \
+                     the layer registry",
+                    input_directories[other]
+                );
+            }
+        }
+
+        // Changes are only worth mentioning once there are layers to change.
+        let is_new = self.layers.is_empty();
+        for (layer, registered) in self.layers.iter_mut().enumerate() {
+            match registered {
+                RegisteredLayer::Material(name) if !inputs.contains_key(name) => {
+                    eprintln!("{name:?} is no longer given, so layer {layer} is now a placeholder");
+                    *registered = RegisteredLayer::Removed(std::mem::take(name));
+                }
+                RegisteredLayer::Removed(name) if inputs.contains_key(name) => {
+                    eprintln!("{name:?} is given again, so it's back on layer {layer}");
+                    *registered = RegisteredLayer::Material(std::mem::take(name));
+                }
+                _ => {}
+            }
+        }
+
+        if compact {
+            let mut compacted = Vec::with_capacity(self.layers.len());
+            for (layer, registered) in std::mem::take(&mut self.layers).into_iter().enumerate() {
+                match registered {
+                    RegisteredLayer::Material(name) => {
+                        if layer != compacted.len() {
+                            eprintln!(
+                                "Moving {name:?} from layer {layer} to layer {}",
+                                compacted.len()
+                            );
+                        }
+                        compacted.push(RegisteredLayer::Material(name));
+                    }
+                    RegisteredLayer::Removed(name) => {
+                        eprintln!("Dropping layer {layer}, the placeholder of {name:?}");
+                    }
+                }
+            }
+            self.layers = compacted;
+        }
+
+        let registered: HashSet<String> = self
+            .layers
+            .iter()
+            .map(|registered| registered.name().to_owned())
+            .collect();
+        for name in names {
+            if !registered.contains(&name) {
+                if !is_new {
+                    eprintln!("Adding {name:?} on layer {}", self.layers.len());
+                }
+                self.layers.push(RegisteredLayer::Material(name));
+            }
+        }
+
+        Ok(self
+            .layers
+            .iter()
+            .map(|registered| match registered {
+                RegisteredLayer::Material(name) => Some(inputs[name]),
+                RegisteredLayer::Removed(_) => None,
+            })
+            .collect())
+    }
+}
+
+impl RegisteredLayer {
+    fn name(&self) -> &str {
+        match self {
+            Self::Material(name) | Self::Removed(name) => name,
+        }
+    }
+}
+
+/// The name a material is registered by, which is that of its input
+/// directory.
+fn material_name(input_directory: &Path) -> anyhow::Result<String> {
+    let name = match input_directory.file_name() {
+        Some(name) => name.to_owned(),
+        // Such as ".", which still names a directory.
+        None => input_directory
+            .canonicalize()
+            .with_context(|| format!("{input_directory:?}"))?
+            .file_name()
+            .with_context(|| format!("{input_directory:?} has no name to register it by"))?
+            .to_owned(),
+    };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RegisteredLayer::{Material, Removed};
+
+    fn dirs(names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| Path::new("in").join(name))
+            .collect()
+    }
+
+    fn registry(layers: &[RegisteredLayer]) -> LayerRegistry {
+        LayerRegistry {
+            layers: layers.to_vec(),
+        }
+    }
+
+    fn material(name: &str) -> RegisteredLayer {
+        Material(name.to_owned())
+    }
+
+    fn removed(name: &str) -> RegisteredLayer {
+        Removed(name.to_owned())
+    }
+
+    #[test]
+    fn new_registry_takes_the_input_order() {
+        let mut registry = LayerRegistry::default();
+        let layers = registry.assign(&dirs(&["a", "b", "c"]), false).unwrap();
+        assert_eq!(layers, [Some(0), Some(1), Some(2)]);
+        assert_eq!(
+            registry.layers,
+            [material("a"), material("b"), material("c")]
+        );
+    }
+
+    #[test]
+    fn materials_keep_their_layers_in_any_order() {
+        let mut registry = registry(&[material("a"), material("b"), material("c")]);
+        let layers = registry.assign(&dirs(&["c", "a", "b"]), false).unwrap();
+        assert_eq!(layers, [Some(1), Some(2), Some(0)]);
+    }
+
+    #[test]
+    fn removed_material_leaves_a_placeholder() {
+        let mut registry = registry(&[material("a"), material("b"), material("c")]);
+        let layers = registry.assign(&dirs(&["a", "c"]), false).unwrap();
+        assert_eq!(layers, [Some(0), None, Some(1)]);
+        assert_eq!(
+            registry.layers,
+            [material("a"), removed("b"), material("c")]
+        );
+    }
+
+    #[test]
+    fn returning_material_gets_its_layer_back() {
+        let mut registry = registry(&[material("a"), removed("b"), material("c")]);
+        let layers = registry.assign(&dirs(&["b", "c", "a"]), false).unwrap();
+        assert_eq!(layers, [Some(2), Some(0), Some(1)]);
+        assert_eq!(
+            registry.layers,
+            [material("a"), material("b"), material("c")]
+        );
+    }
+
+    #[test]
+    fn compacting_drops_placeholders() {
+        let mut registry = registry(&[removed("a"), material("b"), removed("c"), material("d")]);
+        let layers = registry.assign(&dirs(&["b", "d"]), true).unwrap();
+        assert_eq!(layers, [Some(0), Some(1)]);
+        assert_eq!(registry.layers, [material("b"), material("d")]);
+    }
+
+    #[test]
+    fn compacting_keeps_materials_that_return() {
+        let mut registry = registry(&[material("a"), removed("b"), material("c")]);
+        let layers = registry.assign(&dirs(&["a", "b"]), true).unwrap();
+        assert_eq!(layers, [Some(0), Some(1)]);
+        assert_eq!(registry.layers, [material("a"), material("b")]);
+    }
+
+    #[test]
+    fn new_material_goes_after_placeholders() {
+        let mut registry = registry(&[material("a"), removed("b")]);
+        let layers = registry.assign(&dirs(&["d", "a"]), false).unwrap();
+        assert_eq!(layers, [Some(1), None, Some(0)]);
+        assert_eq!(
+            registry.layers,
+            [material("a"), removed("b"), material("d")]
+        );
+    }
+
+    #[test]
+    fn inputs_with_the_same_name_are_rejected() {
+        let mut registry = LayerRegistry::default();
+        let inputs = [Path::new("x").join("a"), Path::new("y").join("a")];
+        let error = registry.assign(&inputs, false).unwrap_err();
+        assert!(
+            error.to_string().contains("are both named \"a\""),
+            "{error}"
+        );
+        assert!(registry.layers.is_empty());
+    }
+}
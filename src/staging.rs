@@ -0,0 +1,80 @@
+use crate::interrupt;
+use crate::plan::Planner;
+use anyhow::Context;
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A directory for the intermediate images given to encoders.
+///
+/// By default it's a private temporary directory that is removed along with
+/// its contents when dropped. With `--keep-intermediates` it's the
+/// "intermediates" directory of the output directory, which is left in place.
+pub struct StagingDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl StagingDir {
+    pub fn new(output_directory: &Path, keep: bool, planner: &Planner) -> anyhow::Result<Self> {
+        let path = if keep {
+            let path = output_directory.join("intermediates");
+            if !planner.is_dry_run() {
+                std::fs::create_dir_all(&path).with_context(|| format!("{path:?}"))?;
+            }
+            path
+        } else if planner.is_dry_run() {
+            temp_dir_path()
+        } else {
+            let path = create_private_temp_dir()?;
+            interrupt::remove_on_exit(&path);
+            path
+        };
+        Ok(Self { path, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            if e.kind() != ErrorKind::NotFound {
+                eprintln!("Failed to remove {:?}: {e}", self.path);
+            }
+        }
+        interrupt::keep_on_exit(&self.path);
+    }
+}
+
+fn temp_dir_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("material-converter-{}-{n}", std::process::id()))
+}
+
+/// Create a new directory in the system's temporary directory that only the
+/// current user can read.
+fn create_private_temp_dir() -> anyhow::Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    loop {
+        let path = temp_dir_path();
+        match builder.create(&path) {
+            Ok(()) => return Ok(path),
+            // Left behind by an earlier process with the same ID.
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("{path:?}")),
+        }
+    }
+}
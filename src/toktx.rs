@@ -1,68 +1,199 @@
-use crate::{Ktx2TextureCodec, MaterialAttribute};
+use crate::encoder::{find_backend, run_command, EncodeJob, Executable, TextureEncoder};
+use crate::plan::Planner;
+use crate::{
+    EncodeOptions, EncoderBackend, Ktx2TextureCodec, MaterialAttribute, MipGeneration, MipOptions,
+    TextureFormat,
+};
+use anyhow::Context;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
-pub fn toktx2(
-    input_path: &Path,
-    attribute: MaterialAttribute,
-    codec: Ktx2TextureCodec,
-    output_path: &Path,
-) -> anyhow::Result<()> {
-    let Ktx2TextureCodec::Astc = codec;
+type Version = (u32, u32, u32);
 
-    let mut args = material_attribute_args(attribute);
-    args.push(output_path.to_str().unwrap());
-    args.push(input_path.to_str().unwrap());
+/// The oldest "toktx" that writes KTX2 files.
+const MIN_VERSION: Version = (4, 0, 0);
 
-    try_run_command("toktx", &args)
-}
+/// The flags we pass with the KTX-Software release that added them to
+/// "toktx", from its release notes. Flags that aren't listed have been there
+/// since `MIN_VERSION`.
+const FLAG_VERSIONS: &[(&str, Version)] = &[
+    // v4.0.0 added ASTC and UASTC encoding behind "--encode", and
+    // "--normalize" for normal maps.
+    ("--encode", (4, 0, 0)),
+    ("--normalize", (4, 0, 0)),
+    ("--uastc_quality", (4, 0, 0)),
+    ("--astc_blk_d", (4, 0, 0)),
+    ("--astc_quality", (4, 0, 0)),
+    ("--astc_perceptual", (4, 0, 0)),
+    // v4.1.0 replaced "--uastc_rdo_q" with "--uastc_rdo_l".
+    ("--uastc_rdo_l", (4, 1, 0)),
+];
 
-pub fn toktx2_array(
-    input_paths: &[PathBuf],
-    attribute: MaterialAttribute,
-    codec: Ktx2TextureCodec,
-    output_path: &Path,
-) -> anyhow::Result<()> {
-    let Ktx2TextureCodec::Astc = codec;
+const EXECUTABLE: Executable = Executable {
+    names: &["toktx"],
+    flag: "--toktx-path",
+    env_var: "TOKTX",
+    install: "Install KTX-Software from https://github.com/KhronosGroup/KTX-Software/releases",
+};
 
-    let mut args = material_attribute_args(attribute);
+/// Encodes with the "toktx" tool from KTX-Software.
+pub struct Toktx;
 
-    let num_layers = input_paths.len();
-    let num_layers_str = format!("{num_layers}");
-    args.push("--layers");
-    args.push(&num_layers_str);
+impl TextureEncoder for Toktx {
+    fn supports(&self, _codec: Ktx2TextureCodec) -> bool {
+        true
+    }
 
-    args.push(output_path.to_str().unwrap());
-    args.extend(input_paths.iter().map(|p| p.to_str().unwrap()));
+    fn encode(&self, job: &EncodeJob, planner: &Planner) -> anyhow::Result<()> {
+        let mut args: Vec<OsString> = material_attribute_args(job.attribute)
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        args.extend(
+            codec_args(job.codec, job.attribute, job.encode)
+                .into_iter()
+                .map(OsString::from),
+        );
 
-    try_run_command("toktx", &args)
-}
+        let num_levels = job.num_levels();
+        args.extend(mip_args(job.mips).iter().map(OsString::from));
+        if job.mips == MipGeneration::Native {
+            args.push(num_levels.to_string().into());
+        }
 
-fn try_run_command(command_name: &str, args: &[&str]) -> anyhow::Result<()> {
-    use std::process::Command;
+        if job.is_array {
+            args.push("--layers".into());
+            args.push(job.layer_paths.len().to_string().into());
+        }
 
-    eprintln!("Running {command_name} with args = {args:?}");
+        args.push(job.partial_path().into());
+        // Input images are ordered by mip level, then by layer.
+        for level in 0..num_levels {
+            args.extend(job.layer_paths.iter().map(|p| p[level].clone().into()));
+        }
 
-    let out = Command::new(command_name).args(args).output()?;
+        let program = job
+            .encode
+            .toktx_path
+            .as_deref()
+            .unwrap_or(Path::new("toktx"));
+        run_command(program, &args, job, planner)
+    }
+}
 
-    if !out.status.success() {
-        let mut error_str = format!("{command_name} failed");
-        if let Some(code) = out.status.code() {
-            error_str.push_str(&format!(" with exit code {code}"));
-        }
-        if let Ok(stderr_str) = std::str::from_utf8(&out.stderr) {
-            if !stderr_str.is_empty() {
-                error_str.push_str(&format!("\nSTDERR = {stderr_str}"));
-            }
-        }
-        if let Ok(stdout_str) = std::str::from_utf8(&out.stdout) {
-            if !stdout_str.is_empty() {
-                error_str.push_str(&format!("\nSTDOUT = {stdout_str}"));
-            }
+/// Find the "toktx" executable if it encodes any of the outputs of
+/// `texture_format`, and check that it supports every flag it will be given.
+///
+/// In a dry run, "toktx" isn't run to get its version, and it's only a warning
+/// if it can't be found.
+pub(crate) fn prepare(
+    encode: &EncodeOptions,
+    texture_format: TextureFormat,
+    mips: &MipOptions,
+    planner: &Planner,
+) -> anyhow::Result<Option<PathBuf>> {
+    let codecs: Vec<_> = MaterialAttribute::ALL
+        .into_iter()
+        .filter(|&attr| encode.encoder(attr) == EncoderBackend::Toktx)
+        .filter_map(|attr| Some((attr, texture_format.encoder_codec(attr)?)))
+        .collect();
+    let Some(path) = find_backend(
+        EncoderBackend::Toktx,
+        &EXECUTABLE,
+        encode.toktx_path.as_deref(),
+        encode,
+        texture_format,
+        planner,
+    )?
+    else {
+        return Ok(None);
+    };
+    if planner.is_dry_run() {
+        return Ok(Some(path));
+    }
+
+    let out = Command::new(&path)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("Couldn't run {path:?}"))?;
+    let version_str = String::from_utf8_lossy(&out.stdout);
+    let Some(version) = parse_version(&version_str) else {
+        if encode.skip_toktx_version_check {
+            return Ok(Some(path));
         }
-        anyhow::bail!(anyhow::anyhow!(error_str));
+        anyhow::bail!(
+            "Couldn't tell the version of {path:?} from {:?}. Pass \
+             --skip-toktx-version-check to use it anyway",
+            version_str.trim()
+        );
+    };
+
+    let mut flags: Vec<String> = mip_args(mips.generation)
+        .iter()
+        .chain(&["--layers"])
+        .map(|&flag| flag.to_owned())
+        .collect();
+    for &(attr, codec) in &codecs {
+        flags.extend(material_attribute_args(attr).into_iter().map(str::to_owned));
+        flags.extend(codec_args(codec, attr, encode));
+    }
+    flags.retain(|arg| arg.starts_with("--"));
+    flags.sort_unstable();
+    flags.dedup();
+
+    let missing: Vec<String> = flags
+        .iter()
+        .map(|flag| (flag, flag_version(flag)))
+        .filter(|(_, needed)| *needed > version)
+        .map(|(flag, needed)| format!("    {flag} needs {}", version_string(needed)))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "{path:?} is version {}, which is too old for these flags:\n{}\nInstall a newer \
+             KTX-Software from https://github.com/KhronosGroup/KTX-Software/releases",
+            version_string(version),
+            missing.join("\n")
+        );
     }
 
-    Ok(())
+    Ok(Some(path))
+}
+
+/// Parse the version out of the output of `toktx --version`, such as
+/// "toktx v4.3.1" or "toktx v4.1.0-rc2".
+fn parse_version(output: &str) -> Option<Version> {
+    let version = output
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix('v'))?;
+    let mut parts = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse::<u32>);
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    let patch = parts.next().and_then(Result::ok).unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+fn flag_version(flag: &str) -> Version {
+    FLAG_VERSIONS
+        .iter()
+        .find_map(|&(f, version)| (f == flag).then_some(version))
+        .unwrap_or(MIN_VERSION)
+}
+
+fn version_string((major, minor, patch): Version) -> String {
+    format!("v{major}.{minor}.{patch}")
+}
+
+/// The mip flags for `mips`, which are followed by the number of levels for
+/// `MipGeneration::Native`.
+fn mip_args(mips: MipGeneration) -> &'static [&'static str] {
+    match mips {
+        MipGeneration::Toktx => &["--genmipmap"],
+        MipGeneration::Native => &["--mipmap", "--levels"],
+        MipGeneration::None => &[],
+    }
 }
 
 fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
@@ -70,12 +201,8 @@ fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
         MaterialAttribute::Albedo => vec![
             "--2d",
             "--t2",
-            "--encode",
-            "astc",
-            "--genmipmap",
             "--target_type",
             "RGBA",
-            "--astc_perceptual",
             "--convert_oetf",
             "srgb",
         ],
@@ -86,9 +213,6 @@ fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
         | MaterialAttribute::Roughness => vec![
             "--2d",
             "--t2",
-            "--encode",
-            "astc",
-            "--genmipmap",
             "--target_type",
             "R",
             "--convert_oetf",
@@ -97,9 +221,6 @@ fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
         MaterialAttribute::MetallicRoughness => vec![
             "--2d",
             "--t2",
-            "--encode",
-            "astc",
-            "--genmipmap",
             "--target_type",
             "RGB",
             "--convert_oetf",
@@ -108,12 +229,8 @@ fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
         MaterialAttribute::Normal => vec![
             "--2d",
             "--t2",
-            "--encode",
-            "astc",
-            "--genmipmap",
             "--target_type",
             "RGB",
-            "--astc_perceptual",
             "--convert_oetf",
             "linear",
             "--normalize",
@@ -124,3 +241,50 @@ fn material_attribute_args(attr: MaterialAttribute) -> Vec<&'static str> {
         ],
     }
 }
+
+fn codec_args(
+    codec: Ktx2TextureCodec,
+    attr: MaterialAttribute,
+    encode: &EncodeOptions,
+) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    match codec {
+        Ktx2TextureCodec::Astc => {
+            args.extend([
+                "--encode".into(),
+                "astc".into(),
+                "--astc_blk_d".into(),
+                encode.astc_block_size(attr).to_string(),
+                "--astc_quality".into(),
+                encode.astc_quality().to_string(),
+            ]);
+            if matches!(attr, MaterialAttribute::Albedo | MaterialAttribute::Normal) {
+                args.push("--astc_perceptual".into());
+            }
+        }
+        Ktx2TextureCodec::Uastc => {
+            args.extend([
+                "--encode".into(),
+                "uastc".into(),
+                "--uastc_quality".into(),
+                encode.uastc_quality.to_string(),
+            ]);
+            if encode.uastc_rdo_lambda > 0.0 {
+                args.extend(["--uastc_rdo_l".into(), encode.uastc_rdo_lambda.to_string()]);
+            }
+        }
+        // BasisLZ is already a supercompression scheme.
+        Ktx2TextureCodec::Etc1s => args.extend([
+            "--encode".into(),
+            "etc1s".into(),
+            "--clevel".into(),
+            encode.etc1s_compression.to_string(),
+            "--qlevel".into(),
+            encode.etc1s_quality.to_string(),
+        ]),
+    }
+    if let (Some(level), Ktx2TextureCodec::Astc | Ktx2TextureCodec::Uastc) = (encode.zstd, codec) {
+        args.extend(["--zcmp".into(), level.to_string()]);
+    }
+    args
+}